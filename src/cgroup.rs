@@ -1,46 +1,389 @@
-use log::{error, info};
-use std::{fs, path::PathBuf};
+use log::{error, info, warn};
+use nix::{
+    sched::{setns, unshare, CloneFlags},
+    sys::stat::{major, minor},
+    unistd::write,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::units;
+
+/// Where the cgroup2 filesystem is mounted on the host
+const CGROUP_ROOT: &str = "/sys/fs/cgroup/";
 
 #[derive(Debug)]
 pub enum CgroupError {
     InvalidName(&'static str),
     IOError(std::io::Error),
+    InvalidIoLimit(String),
+    NotABlockDevice(PathBuf),
+}
+
+impl std::fmt::Display for CgroupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CgroupError::InvalidName(reason) => write!(f, "invalid cgroup: {}", reason),
+            CgroupError::IOError(e) => write!(f, "{}", e),
+            CgroupError::InvalidIoLimit(spec) => write!(f, "invalid io limit {}", spec),
+            CgroupError::NotABlockDevice(path) => write!(f, "{:?} is not on a block device", path),
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, CgroupError>;
 
+/// Largest value of cpu.weight, the default one being 100
+pub const MAX_CPU_WEIGHT: u32 = 10000;
+
+/// Largest value of io.weight, the default one being 100
+pub const MAX_IO_WEIGHT: u32 = 10000;
+
+/// Returns the major:minor of the disk holding path, path being a block
+/// device or a file on one. io.max only accepts whole disks, not partitions.
+pub fn block_device(path: &Path) -> Result<String> {
+    let metadata = fs::metadata(path).map_err(CgroupError::IOError)?;
+
+    let dev = match metadata.file_type().is_block_device() {
+        true => metadata.rdev(),
+        false => metadata.dev(),
+    };
+
+    let sys_path = PathBuf::from(format!("/sys/dev/block/{}:{}", major(dev), minor(dev)));
+
+    // tmpfs, overlay and the like have no block device
+    let sys_path = match fs::canonicalize(&sys_path) {
+        Ok(p) => p,
+        Err(_) => {
+            error!(target:"cgroup_io", "{:?} is not on a block device", path);
+            return Err(CgroupError::NotABlockDevice(PathBuf::from(path)));
+        }
+    };
+
+    // The directory of a partition is inside the one of its disk
+    let disk = match sys_path.join("partition").exists() {
+        true => sys_path.parent().unwrap_or(&sys_path),
+        false => &sys_path,
+    };
+
+    match fs::read_to_string(disk.join("dev")) {
+        Ok(d) => Ok(String::from(d.trim())),
+        Err(e) => Err(CgroupError::IOError(e)),
+    }
+}
+
+/// Limits of io.max for one disk, unset ones are not limited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoMax {
+    /// major:minor
+    pub device: String,
+    /// Bytes per second
+    pub rbps: Option<u64>,
+    pub wbps: Option<u64>,
+    /// Operations per second
+    pub riops: Option<u64>,
+    pub wiops: Option<u64>,
+}
+
+impl IoMax {
+    /// Parses path:key=value[,key=value...], keys being rbps, wbps, riops
+    /// and wiops. Rates in bytes take units, as 10m.
+    pub fn parse(spec: &str) -> Result<IoMax> {
+        let invalid = || CgroupError::InvalidIoLimit(String::from(spec));
+
+        let (path, limits) = spec.rsplit_once(':').ok_or_else(invalid)?;
+
+        let mut io_max = IoMax {
+            device: block_device(Path::new(path))?,
+            rbps: None,
+            wbps: None,
+            riops: None,
+            wiops: None,
+        };
+
+        for limit in limits.split(',') {
+            let (key, value) = limit.split_once('=').ok_or_else(invalid)?;
+
+            match key {
+                "rbps" => io_max.rbps = Some(units::parse_size(value).ok_or_else(invalid)?),
+                "wbps" => io_max.wbps = Some(units::parse_size(value).ok_or_else(invalid)?),
+                "riops" => io_max.riops = Some(value.parse().map_err(|_| invalid())?),
+                "wiops" => io_max.wiops = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(io_max)
+    }
+}
+
+impl fmt::Display for IoMax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = |limit: Option<u64>| match limit {
+            Some(l) => l.to_string(),
+            None => String::from("max"),
+        };
+
+        write!(
+            f,
+            "{} rbps={} wbps={} riops={} wiops={}",
+            self.device,
+            value(self.rbps),
+            value(self.wbps),
+            value(self.riops),
+            value(self.wiops)
+        )
+    }
+}
+
+/// A list of CPUs or memory nodes in the format of cpuset.cpus, as 0-3,6
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet {
+    /// Inclusive ranges
+    ranges: Vec<(u32, u32)>,
+}
+
+impl CpuSet {
+    pub fn parse(list: &str) -> Option<CpuSet> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+
+        for range in list.trim().split(',') {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                None => {
+                    let n = range.parse().ok()?;
+                    (n, n)
+                }
+            };
+
+            if start > end {
+                return None;
+            }
+
+            ranges.push((start, end));
+        }
+
+        Some(CpuSet { ranges })
+    }
+
+    /// Number of CPUs or nodes in the list, counted once each
+    pub fn count(&self) -> u64 {
+        let mut ranges = self.ranges.clone();
+        ranges.sort_unstable();
+
+        let mut count: u64 = 0;
+        let mut next: u64 = 0;
+
+        // Overlapping ranges are merged
+        for (start, end) in ranges {
+            let start = (start as u64).max(next);
+            let end = end as u64 + 1;
+
+            if end > start {
+                count += end - start;
+                next = end;
+            }
+        }
+
+        count
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ranges: Vec<String> = self
+            .ranges
+            .iter()
+            .map(|(start, end)| match start == end {
+                true => start.to_string(),
+                false => format!("{}-{}", start, end),
+            })
+            .collect();
+
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
 pub struct CgroupV2Builder {
     name: String,
-    pids: Vec<u32>,
     max_mem: Option<u64>,
+    high_mem: Option<u64>,
+    max_swap: Option<u64>,
+    oom_group: bool,
     max_pids: Option<u32>,
-    cpus: Option<u32>,
+    /// Quota and period in microseconds
+    max_cpu: Option<(u64, u64)>,
+    cpu_weight: Option<u32>,
+    cpuset_cpus: Option<CpuSet>,
+    cpuset_mems: Option<CpuSet>,
+    io_max: Vec<IoMax>,
+    io_weight: Option<u32>,
 }
 
 pub struct CgroupV2 {
     name: String,
-    path: PathBuf,
+    /// Opened on creation, the cgroup filesystem is not mounted in the sandbox
+    dir: File,
+    /// The cgroup root, to remove the cgroup from the sandbox
+    parent: File,
+}
+
+/// Counters of memory.events, since the creation of the cgroup
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryEvents {
+    /// Times the processes were throttled above memory.high
+    pub high: u64,
+    /// Times memory.max was about to be exceeded
+    pub max: u64,
+    pub oom: u64,
+    /// Processes killed by the OOM killer
+    pub oom_kill: u64,
+}
+
+/// memory.peak reset at the start of a run
+pub struct PeakWatch {
+    file: File,
+}
+
+impl PeakWatch {
+    /// Most bytes of memory used since the reset
+    pub fn read(&self) -> Result<u64> {
+        let mut content = String::new();
+        let mut file = &self.file;
+
+        let read = file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_to_string(&mut content));
+
+        if let Err(e) = read {
+            error!(target:"cgroup", "cannot read memory.peak: {}", e);
+            return Err(CgroupError::IOError(e));
+        }
+
+        match content.trim().parse() {
+            Ok(p) => Ok(p),
+            Err(_) => Err(CgroupError::IOError(std::io::Error::from(
+                std::io::ErrorKind::InvalidData,
+            ))),
+        }
+    }
+}
+
+/// Counters of cpu.stat, in microseconds
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct CpuStat {
+    pub usage_usec: u64,
+    pub user_usec: u64,
+    pub system_usec: u64,
+    /// Periods during which cpu.max was reached
+    pub nr_throttled: u64,
+    pub throttled_usec: u64,
+}
+
+/// Counters of io.stat for one device
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct IoStat {
+    /// major:minor
+    pub device: String,
+    pub rbytes: u64,
+    pub wbytes: u64,
+    pub rios: u64,
+    pub wios: u64,
+}
+
+impl MemoryEvents {
+    /// Events which happened after before was read
+    pub fn since(&self, before: &MemoryEvents) -> MemoryEvents {
+        MemoryEvents {
+            high: self.high.saturating_sub(before.high),
+            max: self.max.saturating_sub(before.max),
+            oom: self.oom.saturating_sub(before.oom),
+            oom_kill: self.oom_kill.saturating_sub(before.oom_kill),
+        }
+    }
+}
+
+impl CpuStat {
+    pub fn since(&self, before: &CpuStat) -> CpuStat {
+        CpuStat {
+            usage_usec: self.usage_usec.saturating_sub(before.usage_usec),
+            user_usec: self.user_usec.saturating_sub(before.user_usec),
+            system_usec: self.system_usec.saturating_sub(before.system_usec),
+            nr_throttled: self.nr_throttled.saturating_sub(before.nr_throttled),
+            throttled_usec: self.throttled_usec.saturating_sub(before.throttled_usec),
+        }
+    }
+}
+
+impl IoStat {
+    /// Devices missing from before were not used yet
+    pub fn since(&self, before: &[IoStat]) -> IoStat {
+        let empty = IoStat::default();
+        let before = before
+            .iter()
+            .find(|b| b.device == self.device)
+            .unwrap_or(&empty);
+
+        IoStat {
+            device: self.device.clone(),
+            rbytes: self.rbytes.saturating_sub(before.rbytes),
+            wbytes: self.wbytes.saturating_sub(before.wbytes),
+            rios: self.rios.saturating_sub(before.rios),
+            wios: self.wios.saturating_sub(before.wios),
+        }
+    }
 }
 
 impl CgroupV2Builder {
     pub fn new(name: &str) -> Self {
         CgroupV2Builder {
             name: String::from(name),
-            pids: Vec::new(),
             max_mem: Option::None,
+            high_mem: Option::None,
+            max_swap: Option::None,
+            oom_group: false,
             max_pids: Option::None,
-            cpus: Option::None,
+            max_cpu: Option::None,
+            cpu_weight: Option::None,
+            cpuset_cpus: Option::None,
+            cpuset_mems: Option::None,
+            io_max: Vec::new(),
+            io_weight: Option::None,
         }
     }
 
-    pub fn add_pid(&mut self, pid: u32) -> &mut Self {
-        self.pids.push(pid);
+    pub fn set_mem_max(&mut self, max: u64) -> &mut Self {
+        self.max_mem = Some(max);
 
         self
     }
 
-    pub fn set_mem_max(&mut self, max: u64) -> &mut Self {
-        self.max_mem = Some(max);
+    /// Above high, the processes are throttled and their memory reclaimed
+    pub fn set_mem_high(&mut self, high: u64) -> &mut Self {
+        self.high_mem = Some(high);
+
+        self
+    }
+
+    /// Makes the OOM killer kill every process of the cgroup at once
+    pub fn set_oom_group(&mut self, enabled: bool) -> &mut Self {
+        self.oom_group = enabled;
+
+        self
+    }
+
+    pub fn set_swap_max(&mut self, max: u64) -> &mut Self {
+        self.max_swap = Some(max);
 
         self
     }
@@ -50,20 +393,56 @@ impl CgroupV2Builder {
         self
     }
 
-    pub fn set_cpus_number(&mut self, n: u32) -> &mut Self {
-        self.cpus = Some(n);
+    /// The cgroup may run quota microseconds every period microseconds
+    pub fn set_cpu_max(&mut self, quota: u64, period: u64) -> &mut Self {
+        self.max_cpu = Some((quota, period));
+
+        self
+    }
+
+    /// Share of the CPU time when CPUs are contended, from 1 to MAX_CPU_WEIGHT
+    pub fn set_cpu_weight(&mut self, weight: u32) -> &mut Self {
+        self.cpu_weight = Some(weight);
+
+        self
+    }
+
+    /// CPUs the processes may run on
+    pub fn set_cpuset_cpus(&mut self, cpus: CpuSet) -> &mut Self {
+        self.cpuset_cpus = Some(cpus);
+
+        self
+    }
+
+    /// Memory nodes the processes may allocate from
+    pub fn set_cpuset_mems(&mut self, mems: CpuSet) -> &mut Self {
+        self.cpuset_mems = Some(mems);
+
+        self
+    }
+
+    /// Limits the bandwidth of a disk, can be called once per disk
+    pub fn add_io_max(&mut self, io_max: IoMax) -> &mut Self {
+        self.io_max.push(io_max);
+
+        self
+    }
+
+    /// Share of the disks when they are contended, from 1 to MAX_IO_WEIGHT
+    pub fn set_io_weight(&mut self, weight: u32) -> &mut Self {
+        self.io_weight = Some(weight);
 
         self
     }
 
     pub fn create(&mut self) -> Result<CgroupV2> {
-        let cgroup_path: PathBuf = PathBuf::from("/sys/fs/cgroup/");
+        let cgroup_path: PathBuf = PathBuf::from(CGROUP_ROOT);
 
         if let Err(e) = fs::create_dir_all(&cgroup_path) {
             return Err(CgroupError::IOError(e));
         }
 
-        // Add cpu, cpuset, memory and pids controllers
+        // Add cpu, cpuset, memory, pids and io controllers
         if let Err(e) = fs::write(cgroup_path.join("cgroup.subtree_control"), "+cpu") {
             return Err(CgroupError::IOError(e));
         }
@@ -80,6 +459,15 @@ impl CgroupV2Builder {
             return Err(CgroupError::IOError(e));
         }
 
+        // Only needed for the io limits, the statistics can do without it
+        if let Err(e) = fs::write(cgroup_path.join("cgroup.subtree_control"), "+io") {
+            if !self.io_max.is_empty() || self.io_weight.is_some() {
+                return Err(CgroupError::IOError(e));
+            }
+
+            warn!(target:"cgroup_io", "io controller unavailable: {}", e);
+        }
+
         let new_group_path: PathBuf = cgroup_path.join(&self.name);
 
         if new_group_path.exists() {
@@ -97,24 +485,44 @@ impl CgroupV2Builder {
 
         info!(target:"cgroup", "{:?} created", new_group_path);
 
-        // Let's add all the pids in the cgroup
-        for pid in &self.pids {
+        // Set the cpus and memory nodes
+        if let Some(cpus) = &self.cpuset_cpus {
             if let Err(e) = fs::write(
-                new_group_path.join("cgroup.procs"),
-                pid.to_string().as_str(),
+                new_group_path.join("cpuset.cpus"),
+                cpus.to_string().as_bytes(),
             ) {
-                error!(target:"cgroup_procs", "{}", e);
+                error!(target:"cgroup_cpus", "{}", e);
                 return Err(CgroupError::IOError(e));
             }
         }
 
-        // Set the cpu limits
-        if let Some(n_cpus) = self.cpus {
+        if let Some(mems) = &self.cpuset_mems {
             if let Err(e) = fs::write(
-                new_group_path.join("cpuset.cpus"),
-                n_cpus.to_string().as_bytes(),
+                new_group_path.join("cpuset.mems"),
+                mems.to_string().as_bytes(),
             ) {
-                error!(target:"cgroup_cpus", "{}", e);
+                error!(target:"cgroup_mems", "{}", e);
+                return Err(CgroupError::IOError(e));
+            }
+        }
+
+        // Set the cpu bandwidth
+        if let Some((quota, period)) = self.max_cpu {
+            if let Err(e) = fs::write(
+                new_group_path.join("cpu.max"),
+                format!("{} {}", quota, period).as_str(),
+            ) {
+                error!(target:"cgroup_cpu", "{}", e);
+                return Err(CgroupError::IOError(e));
+            }
+        }
+
+        if let Some(weight) = self.cpu_weight {
+            if let Err(e) = fs::write(
+                new_group_path.join("cpu.weight"),
+                weight.to_string().as_bytes(),
+            ) {
+                error!(target:"cgroup_cpu", "{}", e);
                 return Err(CgroupError::IOError(e));
             }
         }
@@ -130,6 +538,52 @@ impl CgroupV2Builder {
             }
         }
 
+        if let Some(high_mem) = self.high_mem {
+            if let Err(e) = fs::write(
+                new_group_path.join("memory.high"),
+                high_mem.to_string().as_str(),
+            ) {
+                error!(target:"cgroup_mem", "{}", e);
+                return Err(CgroupError::IOError(e));
+            }
+        }
+
+        if self.oom_group {
+            if let Err(e) = fs::write(new_group_path.join("memory.oom.group"), "1") {
+                error!(target:"cgroup_mem", "{}", e);
+                return Err(CgroupError::IOError(e));
+            }
+        }
+
+        // Set the swap limit
+        if let Some(max_swap) = self.max_swap {
+            if let Err(e) = fs::write(
+                new_group_path.join("memory.swap.max"),
+                max_swap.to_string().as_str(),
+            ) {
+                error!(target:"cgroup_swap", "{}", e);
+                return Err(CgroupError::IOError(e));
+            }
+        }
+
+        // Set the io limits
+        for io_max in &self.io_max {
+            if let Err(e) = fs::write(new_group_path.join("io.max"), io_max.to_string().as_str()) {
+                error!(target:"cgroup_io", "{}", e);
+                return Err(CgroupError::IOError(e));
+            }
+        }
+
+        if let Some(weight) = self.io_weight {
+            if let Err(e) = fs::write(
+                new_group_path.join("io.weight"),
+                format!("default {}", weight).as_str(),
+            ) {
+                error!(target:"cgroup_io", "{}", e);
+                return Err(CgroupError::IOError(e));
+            }
+        }
+
         // Set the pids limit
         if let Some(max_pids) = self.max_pids {
             if let Err(e) = fs::write(
@@ -141,16 +595,235 @@ impl CgroupV2Builder {
             }
         }
 
+        let dir = File::open(&new_group_path).map_err(CgroupError::IOError)?;
+        let parent = File::open(&cgroup_path).map_err(CgroupError::IOError)?;
+
         Ok(CgroupV2 {
             name: String::from(&self.name),
-            path: new_group_path,
+            dir,
+            parent,
         })
     }
 }
 
 impl CgroupV2 {
+    /// Returns an existing cgroup, such as the one of a named sandbox
+    pub fn open(name: &str) -> Result<CgroupV2> {
+        let path: PathBuf = PathBuf::from(CGROUP_ROOT).join(name);
+
+        if !path.is_dir() {
+            error!(target:"cgroup", "{:?} does not exist", path);
+            return Err(CgroupError::InvalidName("Cgroup does not exist"));
+        }
+
+        let dir = File::open(&path).map_err(CgroupError::IOError)?;
+        let parent = File::open(CGROUP_ROOT).map_err(CgroupError::IOError)?;
+
+        Ok(CgroupV2 {
+            name: String::from(name),
+            dir,
+            parent,
+        })
+    }
+
+    /// Reads a file of the cgroup, also from inside the sandbox. Returns None
+    /// if the kernel or the enabled controllers do not provide it.
+    fn read_optional(&self, file: &str) -> Result<Option<String>> {
+        let path = PathBuf::from(format!("/proc/self/fd/{}", self.dir.as_raw_fd())).join(file);
+
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                error!(target:"cgroup", "cannot read {}: {}", file, e);
+                Err(CgroupError::IOError(e))
+            }
+        }
+    }
+
+    fn read(&self, file: &str) -> Result<String> {
+        match self.read_optional(file)? {
+            Some(content) => Ok(content),
+            None => {
+                error!(target:"cgroup", "{} does not exist", file);
+                Err(CgroupError::IOError(std::io::Error::from(
+                    std::io::ErrorKind::NotFound,
+                )))
+            }
+        }
+    }
+
+    /// Reads a file holding a single number
+    fn read_number(&self, file: &str) -> Result<Option<u64>> {
+        match self.read_optional(file)? {
+            Some(content) => Ok(content.trim().parse().ok()),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a file made of "key value" lines, as memory.events or cpu.stat
+    fn read_keyed(&self, file: &str) -> Result<BTreeMap<String, u64>> {
+        let content = self.read(file)?;
+
+        Ok(content
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(' ')?;
+                Some((String::from(key), value.trim().parse().ok()?))
+            })
+            .collect())
+    }
+
+    pub fn memory_events(&self) -> Result<MemoryEvents> {
+        let events = self.read_keyed("memory.events")?;
+
+        let get = |key: &str| events.get(key).copied().unwrap_or(0);
+
+        Ok(MemoryEvents {
+            high: get("high"),
+            max: get("max"),
+            oom: get("oom"),
+            oom_kill: get("oom_kill"),
+        })
+    }
+
+    pub fn cpu_stat(&self) -> Result<CpuStat> {
+        let stat = self.read_keyed("cpu.stat")?;
+
+        let get = |key: &str| stat.get(key).copied().unwrap_or(0);
+
+        Ok(CpuStat {
+            usage_usec: get("usage_usec"),
+            user_usec: get("user_usec"),
+            system_usec: get("system_usec"),
+            nr_throttled: get("nr_throttled"),
+            throttled_usec: get("throttled_usec"),
+        })
+    }
+
+    /// Bytes of memory used right now
+    pub fn memory_current(&self) -> Result<Option<u64>> {
+        self.read_number("memory.current")
+    }
+
+    /// Most bytes of memory ever used, since Linux 5.19
+    pub fn memory_peak(&self) -> Result<Option<u64>> {
+        self.read_number("memory.peak")
+    }
+
+    /// Breakdown of the memory used, in bytes or events
+    pub fn memory_stat(&self) -> Result<BTreeMap<String, u64>> {
+        self.read_keyed("memory.stat")
+    }
+
+    /// Most processes ever running at once, since Linux 6.1
+    pub fn pids_peak(&self) -> Result<Option<u64>> {
+        self.read_number("pids.peak")
+    }
+
+    /// Per device counters, empty without the io controller
+    pub fn io_stat(&self) -> Result<Vec<IoStat>> {
+        let content = match self.read_optional("io.stat")? {
+            Some(c) => c,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut stats: Vec<IoStat> = Vec::new();
+
+        // As "8:0 rbytes=90112 wbytes=0 rios=3 wios=0 dbytes=0 dios=0"
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+
+            let mut stat = match fields.next() {
+                Some(device) => IoStat {
+                    device: String::from(device),
+                    ..IoStat::default()
+                },
+                None => continue,
+            };
+
+            for field in fields {
+                let (key, value) = match field.split_once('=') {
+                    Some((k, v)) => (k, v.parse().unwrap_or(0)),
+                    None => continue,
+                };
+
+                match key {
+                    "rbytes" => stat.rbytes = value,
+                    "wbytes" => stat.wbytes = value,
+                    "rios" => stat.rios = value,
+                    "wios" => stat.wios = value,
+                    _ => (),
+                }
+            }
+
+            stats.push(stat);
+        }
+
+        Ok(stats)
+    }
+
+    /// Returns a hook moving the calling process into the cgroup, then into
+    /// the cgroup namespace ns, or a new one rooted at the cgroup if None. It
+    /// runs between fork and exec, while the process is still root.
+    pub fn enter_hook(
+        &self,
+        ns: Option<File>,
+    ) -> Result<impl FnMut() -> std::io::Result<()> + Send + Sync + 'static> {
+        let path = PathBuf::from(format!("/proc/self/fd/{}", self.dir.as_raw_fd()));
+
+        // Opened now, nothing may be allocated after the fork
+        let procs = match OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+        {
+            Ok(f) => f,
+            Err(e) => {
+                error!(target:"cgroup_procs", "{}", e);
+                return Err(CgroupError::IOError(e));
+            }
+        };
+
+        Ok(move || {
+            // 0 stands for the writing process
+            write(procs.as_raw_fd(), b"0")?;
+
+            match &ns {
+                Some(ns) => setns(ns.as_raw_fd(), CloneFlags::CLONE_NEWCGROUP)?,
+                None => unshare(CloneFlags::CLONE_NEWCGROUP)?,
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Opens memory.peak to measure the peak of a single run. Returns None if
+    /// the kernel cannot reset it, before Linux 6.12.
+    pub fn watch_memory_peak(&self) -> Option<PeakWatch> {
+        let path = PathBuf::from(format!("/proc/self/fd/{}", self.dir.as_raw_fd()));
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.join("memory.peak"))
+            .ok()?;
+
+        // The reset only applies to reads through this descriptor
+        match file.write_all(b"reset") {
+            Ok(()) => Some(PeakWatch { file }),
+            Err(e) => {
+                info!(target:"cgroup", "memory.peak cannot be reset: {}", e);
+                None
+            }
+        }
+    }
+
+    /// The cgroup must not have any process left
     pub fn destroy(&self) -> Result<()> {
-        if let Err(e) = fs::remove_dir_all(&self.path) {
+        let parent = PathBuf::from(format!("/proc/self/fd/{}", self.parent.as_raw_fd()));
+
+        // The files of a cgroup cannot be removed, only its directory
+        if let Err(e) = fs::remove_dir(parent.join(&self.name)) {
             error!(target:"cgroup_destroy", "{}", e);
             return Err(CgroupError::IOError(e));
         }
@@ -159,4 +832,28 @@ impl CgroupV2 {
     }
 }
 
-impl CgroupV2 {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cpu_sets() {
+        let set = CpuSet::parse("0-3,8,10-11").unwrap();
+
+        assert_eq!(set.count(), 7);
+        assert_eq!(set.to_string(), "0-3,8,10-11");
+    }
+
+    #[test]
+    fn count_overlapping_ranges_once() {
+        assert_eq!(CpuSet::parse("0-3,2-5").unwrap().count(), 6);
+        assert_eq!(CpuSet::parse("4,0-7,4").unwrap().count(), 8);
+    }
+
+    #[test]
+    fn parse_invalid_cpu_sets() {
+        for invalid in ["", "3-1", "a", "0-", "1,,2", "-1"] {
+            assert!(CpuSet::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+}
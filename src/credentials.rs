@@ -0,0 +1,376 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{info, warn};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+#[derive(Debug)]
+pub enum CredentialsError {
+    IOError(std::io::Error),
+    Parse,
+    Helper(String),
+}
+
+impl std::fmt::Display for CredentialsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CredentialsError::IOError(e) => write!(f, "{}", e),
+            CredentialsError::Parse => write!(f, "cannot parse the docker config"),
+            CredentialsError::Helper(message) => write!(f, "credential helper failed: {}", message),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, CredentialsError>;
+
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    Basic {
+        username: String,
+        password: String,
+    },
+    /// OAuth2 refresh token exchanged against the token service
+    IdentityToken(String),
+    /// Bearer token sent as is to the registry
+    RegistryToken(String),
+}
+
+#[derive(Deserialize, Default)]
+struct AuthEntry {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+    registrytoken: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(rename = "credHelpers", default)]
+    cred_helpers: HashMap<String, String>,
+    #[serde(rename = "credsStore")]
+    creds_store: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HelperOutput {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+/// Builds Basic credentials from a username and a file containing the password
+pub fn from_password_file(username: &str, password_file: &Path) -> Result<Credentials> {
+    let password = match fs::read_to_string(password_file) {
+        Ok(p) => p,
+        Err(e) => return Err(CredentialsError::IOError(e)),
+    };
+
+    Ok(Credentials::Basic {
+        username: String::from(username),
+        password: String::from(password.trim_end_matches(['\n', '\r'])),
+    })
+}
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("DOCKER_CONFIG") {
+        return Some(PathBuf::from(dir).join("config.json"));
+    }
+
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".docker").join("config.json"))
+}
+
+/// Returns the registry host of a config.json key such as https://index.docker.io/v1/
+fn normalize_server(server: &str) -> &str {
+    let server = server
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+
+    let host = server.split('/').next().unwrap_or(server);
+
+    match host {
+        "index.docker.io" | "registry-1.docker.io" => DOCKER_HUB,
+        h => h,
+    }
+}
+
+/// The address the credential helpers know the registry by
+fn server_url(registry: &str) -> &str {
+    if registry == DOCKER_HUB {
+        DOCKER_HUB_SERVER
+    } else {
+        registry
+    }
+}
+
+fn decode_entry(entry: &AuthEntry) -> Result<Option<Credentials>> {
+    if let Some(token) = &entry.registrytoken {
+        return Ok(Some(Credentials::RegistryToken(token.clone())));
+    }
+
+    if let Some(token) = &entry.identitytoken {
+        return Ok(Some(Credentials::IdentityToken(token.clone())));
+    }
+
+    if let (Some(username), Some(password)) = (&entry.username, &entry.password) {
+        return Ok(Some(Credentials::Basic {
+            username: username.clone(),
+            password: password.clone(),
+        }));
+    }
+
+    let auth = match &entry.auth {
+        Some(a) if !a.is_empty() => a,
+        _ => return Ok(None),
+    };
+
+    let decoded = match STANDARD.decode(auth) {
+        Ok(d) => d,
+        Err(_) => return Err(CredentialsError::Parse),
+    };
+
+    match String::from_utf8_lossy(&decoded).split_once(':') {
+        Some((username, password)) => Ok(Some(Credentials::Basic {
+            username: String::from(username),
+            password: String::from(password),
+        })),
+        None => Err(CredentialsError::Parse),
+    }
+}
+
+/// Asks docker-credential-<helper> for the credentials of a registry
+fn run_helper(helper: &str, registry: &str) -> Result<Option<Credentials>> {
+    let program = format!("docker-credential-{}", helper);
+
+    info!(target:"credentials", "asking {} for {}", program, registry);
+
+    let mut child = match Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(c) => c,
+        Err(e) => return Err(CredentialsError::IOError(e)),
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Err(e) = stdin.write_all(server_url(registry).as_bytes()) {
+            return Err(CredentialsError::IOError(e));
+        }
+    }
+
+    let output = match child.wait_with_output() {
+        Ok(o) => o,
+        Err(e) => return Err(CredentialsError::IOError(e)),
+    };
+
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stdout);
+
+        // Not an error, the helper simply has nothing for this registry
+        if message.contains("credentials not found") {
+            return Ok(None);
+        }
+
+        return Err(CredentialsError::Helper(String::from(message.trim())));
+    }
+
+    let data = match serde_json::from_slice::<HelperOutput>(&output.stdout) {
+        Ok(d) => d,
+        Err(_) => return Err(CredentialsError::Parse),
+    };
+
+    if data.username == "<token>" {
+        return Ok(Some(Credentials::IdentityToken(data.secret)));
+    }
+
+    Ok(Some(Credentials::Basic {
+        username: data.username,
+        password: data.secret,
+    }))
+}
+
+/// Looks the credentials of a registry up in the docker config.json
+pub fn lookup(registry: &str) -> Result<Option<Credentials>> {
+    let path = match config_path() {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+
+    let data = match fs::read(&path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(CredentialsError::IOError(e)),
+    };
+
+    let config = match serde_json::from_slice::<DockerConfig>(&data) {
+        Ok(c) => c,
+        Err(_) => {
+            warn!(target:"credentials", "cannot parse {:?}", path);
+            return Err(CredentialsError::Parse);
+        }
+    };
+
+    find(&config, registry, run_helper)
+}
+
+/// Same precedence as docker: per registry helper, default store, then inline
+/// auths. helper asks a credential helper, as run_helper does.
+fn find<F>(config: &DockerConfig, registry: &str, helper: F) -> Result<Option<Credentials>>
+where
+    F: Fn(&str, &str) -> Result<Option<Credentials>>,
+{
+    for (server, name) in &config.cred_helpers {
+        if normalize_server(server) == registry {
+            return helper(name, registry);
+        }
+    }
+
+    if let Some(store) = &config.creds_store {
+        if let Some(credentials) = helper(store, registry)? {
+            return Ok(Some(credentials));
+        }
+    }
+
+    for (server, entry) in &config.auths {
+        if normalize_server(server) == registry {
+            info!(target:"credentials", "found {} in the auths", registry);
+            return decode_entry(entry);
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> DockerConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn entry(json: &str) -> AuthEntry {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// A helper which knows every registry, answering with its own name
+    fn helper(name: &str, _registry: &str) -> Result<Option<Credentials>> {
+        Ok(Some(Credentials::RegistryToken(String::from(name))))
+    }
+
+    fn no_helper(_name: &str, _registry: &str) -> Result<Option<Credentials>> {
+        Ok(None)
+    }
+
+    fn token(credentials: Option<Credentials>) -> Option<String> {
+        match credentials {
+            Some(Credentials::RegistryToken(t)) => Some(t),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn decode_auth() {
+        // base64 of user:pa:ss, the password may contain colons
+        let credentials = decode_entry(&entry(r#"{"auth": "dXNlcjpwYTpzcw=="}"#)).unwrap();
+
+        assert!(matches!(
+            credentials,
+            Some(Credentials::Basic { username, password }) if username == "user" && password == "pa:ss"
+        ));
+    }
+
+    #[test]
+    fn decode_other_entries() {
+        assert!(matches!(
+            decode_entry(&entry(r#"{"username": "u", "password": "p"}"#)).unwrap(),
+            Some(Credentials::Basic { .. })
+        ));
+        assert!(matches!(
+            decode_entry(&entry(r#"{"identitytoken": "t", "auth": "dTpw"}"#)).unwrap(),
+            Some(Credentials::IdentityToken(t)) if t == "t"
+        ));
+        assert!(decode_entry(&entry(r#"{"auth": ""}"#)).unwrap().is_none());
+        assert!(decode_entry(&entry("{}")).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_invalid_auth() {
+        assert!(matches!(
+            decode_entry(&entry(r#"{"auth": "not base64!"}"#)),
+            Err(CredentialsError::Parse)
+        ));
+        // base64 of user, without password
+        assert!(matches!(
+            decode_entry(&entry(r#"{"auth": "dXNlcg=="}"#)),
+            Err(CredentialsError::Parse)
+        ));
+    }
+
+    #[test]
+    fn normalized_servers() {
+        assert_eq!(normalize_server("https://index.docker.io/v1/"), "docker.io");
+        assert_eq!(normalize_server("registry-1.docker.io"), "docker.io");
+        assert_eq!(normalize_server("docker.io"), "docker.io");
+        assert_eq!(
+            normalize_server("http://registry.example:5000/v2/"),
+            "registry.example:5000"
+        );
+        assert_eq!(server_url("docker.io"), DOCKER_HUB_SERVER);
+    }
+
+    #[test]
+    fn helpers_come_first() {
+        let config = config(
+            r#"{"auths": {"https://index.docker.io/v1/": {"auth": "dTpw"}},
+                "credHelpers": {"docker.io": "registry"},
+                "credsStore": "store"}"#,
+        );
+
+        assert_eq!(
+            token(find(&config, "docker.io", helper).unwrap()).as_deref(),
+            Some("registry")
+        );
+    }
+
+    #[test]
+    fn store_before_auths() {
+        let config = config(
+            r#"{"auths": {"https://index.docker.io/v1/": {"auth": "dTpw"}},
+                "credHelpers": {"ghcr.io": "registry"},
+                "credsStore": "store"}"#,
+        );
+
+        assert_eq!(
+            token(find(&config, "docker.io", helper).unwrap()).as_deref(),
+            Some("store")
+        );
+
+        // The auths are used when the store has nothing
+        assert!(matches!(
+            find(&config, "docker.io", no_helper).unwrap(),
+            Some(Credentials::Basic { username, .. }) if username == "u"
+        ));
+    }
+
+    #[test]
+    fn unknown_registry() {
+        let config = config(r#"{"auths": {"docker.io": {"auth": "dTpw"}}}"#);
+
+        assert!(find(&config, "ghcr.io", helper).unwrap().is_none());
+    }
+}
@@ -1,13 +1,25 @@
 use std::{
     collections::HashMap,
-    path::{Path, PathBuf},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    time::Duration,
 };
 
 use flate2::read::GzDecoder;
-use log::info;
-use reqwest::header;
-use serde::Deserialize;
-use tar::Archive;
+use log::{error, info, warn};
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::credentials::{self, Credentials};
+use crate::image_cache::{self, CacheError, ImageCache};
+use crate::layer::{self, ExtractOptions, ExtractReport, LayerError};
+use crate::platform::Platform;
+use crate::reference::{ImageReference, ReferenceError};
 
 #[derive(Debug)]
 pub enum DockerError {
@@ -15,31 +27,49 @@ pub enum DockerError {
     Parse,
     Http(u16),
     Extract,
-    InvalidImage,
+    InvalidImage(ReferenceError),
     ArchitectureNotFound,
-    Unpack,
+    Unpack(LayerError),
+    DigestMismatch(String),
+    /// A digest whose algorithm is not sha256, such as sha512:<hex>
+    UnsupportedDigest(String),
+    UnsupportedMediaType(String),
+    Cache(CacheError),
+    /// The image is not cached and the registry may not be contacted
+    NotCached(String),
 }
 
-#[derive(Deserialize)]
-struct AuthData {
-    token: String,
-    access_token: String,
-    expires_in: i32,
-    issued_at: String,
+impl std::fmt::Display for DockerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DockerError::RequestFailed => write!(f, "cannot reach the registry"),
+            DockerError::Parse => write!(f, "cannot parse the registry response"),
+            DockerError::Http(status) => write!(f, "registry answered {}", status),
+            DockerError::Extract => write!(f, "cannot extract the image"),
+            DockerError::InvalidImage(e) => write!(f, "invalid image: {}", e),
+            DockerError::ArchitectureNotFound => write!(f, "no image for this platform"),
+            DockerError::Unpack(e) => write!(f, "cannot unpack a layer: {}", e),
+            DockerError::DigestMismatch(digest) => write!(f, "content does not match {}", digest),
+            DockerError::UnsupportedDigest(digest) => write!(f, "unsupported digest {}", digest),
+            DockerError::UnsupportedMediaType(media_type) => {
+                write!(f, "unsupported media type {}", media_type)
+            }
+            DockerError::Cache(e) => write!(f, "cache: {}", e),
+            DockerError::NotCached(image) => write!(f, "{} is not cached", image),
+        }
+    }
 }
 
 #[derive(Deserialize)]
-struct Platform {
-    architecture: String,
-    os: String,
+struct AuthData {
+    token: Option<String>,
+    access_token: Option<String>,
 }
 
 #[derive(Deserialize)]
 struct Manifest {
     digest: String,
-    mediaType: String,
-    platform: Platform,
-    size: i32,
+    platform: Option<Platform>,
 }
 
 #[derive(Deserialize)]
@@ -47,20 +77,45 @@ struct ManifestsData {
     manifests: Vec<Manifest>,
 }
 
+#[derive(Deserialize)]
+struct MediaTypeField {
+    #[serde(rename = "mediaType")]
+    media_type: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct Config {
-    mediaType: String,
-    size: i32,
     digest: String,
 }
 
 #[derive(Deserialize)]
 struct Layer {
-    mediaType: String,
-    size: i32,
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    size: Option<u64>,
     digest: String,
 }
 
+/// Runtime settings of an image, from the config section of its config blob
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+pub struct ImageConfig {
+    #[serde(rename = "Entrypoint")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(rename = "Cmd")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(rename = "Env")]
+    pub env: Option<Vec<String>>,
+    #[serde(rename = "WorkingDir")]
+    pub working_dir: Option<String>,
+    #[serde(rename = "User")]
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    config: Option<ImageConfig>,
+}
+
 #[derive(Deserialize)]
 struct ManifestLayers {
     schemaVersion: i32,
@@ -71,160 +126,929 @@ struct ManifestLayers {
 
 type Result<T> = std::result::Result<T, DockerError>;
 
-fn get_auth_token(image_name: &str) -> Result<String> {
-    let auth_url = format!(
-        "https://auth.docker.io/token?service=registry.docker.io&scope=repository:{}:pull",
-        image_name
-    );
+const MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const MANIFEST_V2: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
 
-    let auth_req = match reqwest::blocking::get(auth_url) {
-        Ok(r) => r,
-        Err(e) => {
-            if let Some(status) = e.status() {
-                return Err(DockerError::Http(status.as_u16()));
-            }
+pub const LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
+pub const LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+pub const LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+const DOCKER_LAYER_TAR: &str = "application/vnd.docker.image.rootfs.diff.tar";
+const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
 
-            return Err(DockerError::RequestFailed);
+const DOWNLOAD_BUFFER_SIZE: usize = 64 * 1024;
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+/// Progress is reported every PROGRESS_STEP bytes when the size is unknown
+const PROGRESS_STEP: u64 = 16 * 1024 * 1024;
+
+/// Settings of the registry requests
+#[derive(Debug, Default)]
+pub struct PullOptions {
+    pub credentials: Option<Credentials>,
+    /// Platform to pick in manifest lists, the host one if None
+    pub platform: Option<Platform>,
+    /// How the layers are written to the rootfs
+    pub extract: ExtractOptions,
+    /// Only use the cached images, never contact the registry
+    pub offline: bool,
+}
+
+/// Checks that data matches a content digest such as sha256:<hex>
+pub fn verify_digest(data: &[u8], digest: &str) -> Result<()> {
+    verify_hash(Sha256::digest(data).as_slice(), digest)
+}
+
+/// Checks a sha256 hash against a content digest such as sha256:<hex>
+pub fn verify_hash(hash: &[u8], digest: &str) -> Result<()> {
+    let expected = match digest.strip_prefix("sha256:") {
+        Some(e) => e,
+        None => {
+            error!(target:"docker_digest", "unsupported digest algorithm {}", digest);
+            return Err(DockerError::UnsupportedDigest(String::from(digest)));
         }
     };
 
-    match auth_req.json::<AuthData>() {
-        Ok(data) => Ok(data.token),
-        Err(_) => Err(DockerError::Parse),
+    let actual: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+
+    if !actual.eq_ignore_ascii_case(expected) {
+        error!(target:"docker_digest", "expected {} got sha256:{}", digest, actual);
+        return Err(DockerError::DigestMismatch(String::from(digest)));
     }
+
+    Ok(())
 }
 
-fn get_manifest(token: &str, image_name: &str, image_version: &str) -> Result<Manifest> {
-    let manifest_url = format!(
-        "https://registry-1.docker.io/v2/{}/manifests/{}",
-        image_name, image_version
-    );
+/// Parses a WWW-Authenticate header such as Bearer realm="...",service="..."
+fn parse_challenge(header: &str) -> (String, HashMap<String, String>) {
+    let (scheme, params) = match header.trim().split_once(' ') {
+        Some((s, p)) => (s, p),
+        None => (header.trim(), ""),
+    };
+
+    let mut parsed: HashMap<String, String> = HashMap::new();
+    let mut chars = params.chars().peekable();
+
+    loop {
+        let key: String = chars
+            .by_ref()
+            .skip_while(|c| *c == ',' || c.is_whitespace())
+            .take_while(|c| *c != '=')
+            .collect();
 
-    let manifest_req = match reqwest::blocking::Client::new()
-        .get(manifest_url)
-        .bearer_auth(token)
-        .header(
-            reqwest::header::ACCEPT,
-            "application/vnd.docker.distribution.manifest.list.v2+json",
-        )
-        .send()
-    {
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            // Quoted values may contain commas, as in scope="repository:a:pull,push"
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ',').collect();
+        }
+
+        parsed.insert(key.trim().to_lowercase(), value);
+    }
+
+    (scheme.to_lowercase(), parsed)
+}
+
+/// Sends a request, turning transport errors and error statuses into DockerError
+fn send(request: RequestBuilder) -> Result<Response> {
+    let response = match request.send() {
         Ok(r) => r,
         Err(e) => {
             if let Some(status) = e.status() {
                 return Err(DockerError::Http(status.as_u16()));
             }
 
+            error!(target:"docker_request", "{}", e);
             return Err(DockerError::RequestFailed);
         }
     };
 
-    let data: ManifestsData = match manifest_req.json::<ManifestsData>() {
+    if !response.status().is_success() {
+        error!(target:"docker_request", "{} {}", response.url(), response.status());
+        return Err(DockerError::Http(response.status().as_u16()));
+    }
+
+    Ok(response)
+}
+
+/// How the requests to the registry are authenticated
+enum Auth {
+    Anonymous,
+    Bearer(String),
+    Basic(String, String),
+}
+
+/// Client for the repository of an image in its registry
+struct Registry {
+    client: Client,
+    base_url: String,
+    auth: Auth,
+}
+
+/// Returns the explicit credentials, or those of the docker config.json
+fn find_credentials(reference: &ImageReference, options: &PullOptions) -> Option<Credentials> {
+    if let Some(c) = &options.credentials {
+        return Some(c.clone());
+    }
+
+    match credentials::lookup(&reference.registry) {
+        Ok(c) => c,
+        Err(e) => {
+            // Public images can still be pulled anonymously
+            warn!(target:"docker_registry", "cannot read credentials: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Exchanges the credentials against a token from the service of a Bearer challenge
+fn get_token(
+    client: &Client,
+    params: &HashMap<String, String>,
+    scope: &str,
+    credentials: Option<&Credentials>,
+) -> Result<String> {
+    let realm = match params.get("realm") {
+        Some(r) => r,
+        None => return Err(DockerError::Parse),
+    };
+
+    let service = params.get("service").cloned().unwrap_or_default();
+
+    info!(target:"docker_registry", "authenticating with {}", realm);
+
+    let request = match credentials {
+        Some(Credentials::RegistryToken(token)) => return Ok(token.clone()),
+        Some(Credentials::IdentityToken(refresh_token)) => client.post(realm).form(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("service", &service),
+            ("scope", scope),
+            ("client_id", "moulinette"),
+        ]),
+        Some(Credentials::Basic { username, password }) => client
+            .get(realm)
+            .query(&[("service", &service), ("scope", &String::from(scope))])
+            .basic_auth(username, Some(password)),
+        None => client
+            .get(realm)
+            .query(&[("service", &service), ("scope", &String::from(scope))]),
+    };
+
+    let auth_data = match send(request)?.json::<AuthData>() {
         Ok(d) => d,
         Err(_) => return Err(DockerError::Parse),
     };
 
-    for manifest in data.manifests {
-        if manifest.platform.architecture.eq("amd64") {
-            return Ok(manifest);
+    match auth_data.token.or(auth_data.access_token) {
+        Some(t) => Ok(t),
+        None => Err(DockerError::Parse),
+    }
+}
+
+impl Registry {
+    /// Authenticates as requested by the WWW-Authenticate challenge of the registry
+    fn connect(reference: &ImageReference, options: &PullOptions) -> Result<Registry> {
+        // Layers may take much longer than the default 30 seconds to download
+        let client = match Client::builder()
+            .connect_timeout(Duration::from_secs(30))
+            .timeout(None)
+            .build()
+        {
+            Ok(c) => c,
+            Err(_) => return Err(DockerError::RequestFailed),
+        };
+        let api_url = reference.api_url();
+
+        let ping = match client.get(format!("{}/", api_url)).send() {
+            Ok(r) => r,
+            Err(e) => {
+                error!(target:"docker_registry", "{}: {}", api_url, e);
+                return Err(DockerError::RequestFailed);
+            }
+        };
+
+        let mut auth = Auth::Anonymous;
+
+        if ping.status() == StatusCode::UNAUTHORIZED {
+            let challenge = match ping.headers().get(header::WWW_AUTHENTICATE) {
+                Some(h) => h.to_str().unwrap_or_default(),
+                None => return Err(DockerError::Http(StatusCode::UNAUTHORIZED.as_u16())),
+            };
+
+            let (scheme, params) = parse_challenge(challenge);
+
+            let credentials = find_credentials(reference, options);
+
+            auth = match (scheme.as_str(), credentials) {
+                ("bearer", c) => {
+                    let scope = format!("repository:{}:pull", reference.repository);
+                    Auth::Bearer(get_token(&client, &params, &scope, c.as_ref())?)
+                }
+                ("basic", Some(Credentials::Basic { username, password })) => {
+                    Auth::Basic(username, password)
+                }
+                _ => {
+                    error!(target:"docker_registry", "cannot answer {} challenge", scheme);
+                    return Err(DockerError::Http(StatusCode::UNAUTHORIZED.as_u16()));
+                }
+            };
+        } else if !ping.status().is_success() {
+            return Err(DockerError::Http(ping.status().as_u16()));
         }
+
+        Ok(Registry {
+            client,
+            base_url: format!("{}/{}", api_url, reference.repository),
+            auth,
+        })
+    }
+
+    /// Sends a GET request for a path relative to the repository, as manifests/<tag>
+    fn get(&self, path: &str, accept: Option<&str>) -> Result<Response> {
+        send(self.request(path, accept))
     }
 
-    Err(DockerError::ArchitectureNotFound)
+    /// Sends a GET request for the content of a blob starting at offset
+    fn get_blob(&self, digest: &str, offset: u64) -> Result<Response> {
+        let mut request = self.request(&format!("blobs/{}", digest), None);
+
+        if offset > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", offset));
+        }
+
+        send(request)
+    }
+
+    fn request(&self, path: &str, accept: Option<&str>) -> RequestBuilder {
+        let mut request = self.client.get(format!("{}/{}", self.base_url, path));
+
+        request = match &self.auth {
+            Auth::Anonymous => request,
+            Auth::Bearer(token) => request.bearer_auth(token),
+            Auth::Basic(username, password) => request.basic_auth(username, Some(password)),
+        };
+
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+
+        request
+    }
 }
 
-fn get_layer(token: &str, image_name: &str, manifest: &Manifest) -> Result<Layer> {
-    let layer_url = format!(
-        "https://registry-1.docker.io/v2/{}/manifests/{}",
-        image_name, manifest.digest
-    );
+/// Returns the media type of a manifest, from its body if the registry did not say
+fn manifest_media_type(content_type: Option<String>, body: &[u8]) -> Option<String> {
+    match content_type {
+        Some(t) if t != "application/json" => Some(t),
+        _ => serde_json::from_slice::<MediaTypeField>(body)
+            .ok()
+            .and_then(|m| m.media_type),
+    }
+}
 
-    let layer_req = match reqwest::blocking::Client::new()
-        .get(layer_url)
-        .bearer_auth(token)
-        .header(reqwest::header::ACCEPT, manifest.mediaType.as_str())
-        .send()
-    {
-        Ok(r) => r,
-        Err(e) => {
-            if let Some(status) = e.status() {
-                return Err(DockerError::Http(status.as_u16()));
-            }
+/// Fetches a manifest and checks it against its digest, returns (media type, body, digest)
+fn get_manifest(
+    registry: &Registry,
+    version: &str,
+    digest: Option<&str>,
+) -> Result<(Option<String>, Vec<u8>, String)> {
+    let accept = [MANIFEST_LIST, OCI_INDEX, MANIFEST_V2, OCI_MANIFEST].join(", ");
 
-            return Err(DockerError::RequestFailed);
+    let manifest_req = registry.get(&format!("manifests/{}", version), Some(&accept))?;
+
+    let header_digest = manifest_req
+        .headers()
+        .get("Docker-Content-Digest")
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
+
+    let content_type = manifest_req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| String::from(h.split(';').next().unwrap_or(h).trim()));
+
+    let body = match manifest_req.bytes() {
+        Ok(b) => b.to_vec(),
+        Err(_) => return Err(DockerError::Parse),
+    };
+
+    let media_type = manifest_media_type(content_type, &body);
+
+    // A manifest fetched by tag can only be checked against what the registry claims
+    let digest = match digest.map(String::from).or(header_digest) {
+        Some(d) => {
+            verify_digest(&body, &d)?;
+            d
         }
+        None => format!("sha256:{:x}", Sha256::digest(&body)),
     };
 
-    let manifest_layers = match layer_req.json::<ManifestLayers>() {
+    Ok((media_type, body, digest))
+}
+
+fn is_index(media_type: Option<&str>, body: &[u8]) -> bool {
+    match media_type {
+        Some(MANIFEST_LIST) | Some(OCI_INDEX) => true,
+        Some(_) => false,
+        // OCI indexes are not required to carry a media type
+        None => serde_json::from_slice::<ManifestsData>(body).is_ok(),
+    }
+}
+
+/// Returns the entry of a manifest list or OCI index matching the platform
+fn select_manifest(body: &[u8], platform: &Platform) -> Result<Manifest> {
+    let data: ManifestsData = match serde_json::from_slice::<ManifestsData>(body) {
+        Ok(d) => d,
+        Err(_) => return Err(DockerError::Parse),
+    };
+
+    // Entries without platform, such as attestations, are not images
+    let mut manifests: Vec<Manifest> = data
+        .manifests
+        .into_iter()
+        .filter(|m| m.platform.is_some())
+        .collect();
+
+    let selected = platform.select(manifests.iter().filter_map(|m| m.platform.as_ref()));
+
+    match selected {
+        Some(i) => Ok(manifests.swap_remove(i)),
+        None => {
+            error!(target:"docker_platform", "no image for {}", platform);
+            Err(DockerError::ArchitectureNotFound)
+        }
+    }
+}
+
+/// Resolves the image manifest of a reference for a platform, returns (digest, body)
+fn resolve_manifest(
+    registry: &Registry,
+    reference: &ImageReference,
+    platform: &Platform,
+) -> Result<(String, Vec<u8>)> {
+    let (media_type, body, digest) =
+        get_manifest(registry, reference.version(), reference.digest.as_deref())?;
+
+    if !is_index(media_type.as_deref(), &body) {
+        info!(target:"docker_platform", "single platform image");
+        return Ok((digest, body));
+    }
+
+    let manifest = select_manifest(&body, platform)?;
+
+    if let Some(p) = &manifest.platform {
+        info!(target:"docker_platform", "selected {}", p);
+    }
+
+    let (_, body, digest) = get_manifest(registry, &manifest.digest, Some(&manifest.digest))?;
+
+    Ok((digest, body))
+}
+
+fn parse_image_manifest(data: &[u8]) -> Result<ManifestLayers> {
+    let manifest_layers = match serde_json::from_slice::<ManifestLayers>(data) {
         Ok(m) => m,
         Err(_) => return Err(DockerError::Parse),
     };
 
-    match manifest_layers.layers.into_iter().last() {
-        Some(l) => Ok(l),
-        None => Err(DockerError::Parse),
+    if manifest_layers.layers.is_empty() {
+        return Err(DockerError::Parse);
     }
+
+    Ok(manifest_layers)
 }
 
-fn download_layer(token: &str, image_name: &str, layer: &Layer, output: &Path) -> Result<()> {
-    let layer_conf_url = format!(
-        "https://registry-1.docker.io/v2/{}/blobs/{}",
-        image_name, layer.digest
-    );
+/// State of a blob being written to the cache
+struct BlobDownload {
+    file: File,
+    hasher: Sha256,
+    offset: u64,
+    size: Option<u64>,
+    next_report: u64,
+}
 
-    let layer_req = match reqwest::blocking::Client::new()
-        .get(layer_conf_url)
-        .bearer_auth(token)
-        .send()
-    {
-        Ok(r) => r,
-        Err(e) => {
-            if let Some(status) = e.status() {
-                return Err(DockerError::Http(status.as_u16()));
+impl BlobDownload {
+    /// Resumes from what an interrupted run left in the partial file. Waits
+    /// for the other runs downloading the same blob, returns None if one of
+    /// them committed or removed the partial file meanwhile.
+    fn open(path: &Path, size: Option<u64>) -> std::io::Result<Option<BlobDownload>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        image_cache::lock_exclusive(&file)?;
+
+        if !image_cache::is_same_file(&file, path) {
+            return Ok(None);
+        }
+
+        let mut hasher = Sha256::new();
+        let offset = io::copy(&mut BufReader::new(&mut file), &mut hasher)?;
+
+        let mut download = BlobDownload {
+            file,
+            hasher,
+            offset,
+            size,
+            next_report: 0,
+        };
+
+        // A partial file larger than the blob cannot be a prefix of it
+        if size.is_some_and(|s| offset > s) {
+            download.restart()?;
+        }
+
+        Ok(Some(download))
+    }
+
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.hasher = Sha256::new();
+        self.offset = 0;
+        self.next_report = 0;
+
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.size == Some(self.offset)
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.offset += data.len() as u64;
+
+        Ok(())
+    }
+
+    fn report(&mut self, digest: &str) {
+        if self.offset < self.next_report {
+            return;
+        }
+
+        match self.size {
+            Some(size) if size > 0 => {
+                info!(target:"docker_download", "{} {}/{} bytes ({}%)",
+                    digest, self.offset, size, self.offset * 100 / size);
+                self.next_report = self.offset + (size / 10).max(1);
+            }
+            _ => {
+                info!(target:"docker_download", "{} {} bytes", digest, self.offset);
+                self.next_report = self.offset + PROGRESS_STEP;
             }
+        }
+    }
+}
+
+/// Streams the rest of a blob into the partial file
+fn fetch_blob(registry: &Registry, digest: &str, download: &mut BlobDownload) -> Result<()> {
+    let resume = download.offset > 0;
+
+    let mut response = registry.get_blob(digest, download.offset)?;
+
+    if resume && response.status() != StatusCode::PARTIAL_CONTENT {
+        warn!(target:"docker_download", "{} cannot be resumed, restarting", digest);
+        if let Err(e) = download.restart() {
+            return Err(DockerError::Cache(CacheError::IOError(e)));
+        }
+    } else if resume {
+        info!(target:"docker_download", "{} resumed at {} bytes", digest, download.offset);
+    }
+
+    let mut buffer = vec![0u8; DOWNLOAD_BUFFER_SIZE];
+
+    loop {
+        let n = match response.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!(target:"docker_download", "{} interrupted: {}", digest, e);
+                return Err(DockerError::RequestFailed);
+            }
+        };
+
+        if let Err(e) = download.write(&buffer[..n]) {
+            return Err(DockerError::Cache(CacheError::IOError(e)));
+        }
+
+        download.report(digest);
+    }
+}
+
+/// Downloads a blob into the cache without holding it in memory, resuming
+/// interrupted transfers with range requests
+fn download_blob(
+    registry: &Registry,
+    digest: &str,
+    size: Option<u64>,
+    cache: &ImageCache,
+) -> Result<()> {
+    let partial_path = cache.partial_path(digest).map_err(DockerError::Cache)?;
+
+    // The partial file stays locked until the blob is committed
+    let mut download = loop {
+        if cache.has_blob(digest) {
+            info!(target:"docker_download", "{} downloaded by another run", digest);
+            return Ok(());
+        }
+
+        match BlobDownload::open(&partial_path, size) {
+            Ok(Some(d)) => break d,
+            Ok(None) => continue,
+            Err(e) => return Err(DockerError::Cache(CacheError::IOError(e))),
+        }
+    };
+
+    let mut attempt: u32 = 1;
+
+    while !download.is_complete() {
+        let offset = download.offset;
+
+        match fetch_blob(registry, digest, &mut download) {
+            Ok(()) if size.is_none() || download.is_complete() => break,
+            // The connection was closed before the end of the blob
+            Ok(()) => warn!(target:"docker_download", "{} truncated", digest),
+            Err(DockerError::RequestFailed) => (),
+            Err(e) => return Err(e),
+        }
+
+        // Only count the attempts which did not make any progress
+        if download.offset == offset {
+            attempt += 1;
+        }
 
+        if attempt > DOWNLOAD_ATTEMPTS {
+            error!(target:"docker_download", "{} failed after {} attempts", digest, DOWNLOAD_ATTEMPTS);
             return Err(DockerError::RequestFailed);
         }
+    }
+
+    // Only verified blobs enter the cache
+    if let Err(e) = verify_hash(download.hasher.finalize().as_slice(), digest) {
+        let _ = fs::remove_file(&partial_path);
+        return Err(e);
+    }
+
+    info!(target:"docker_download", "{} complete", digest);
+
+    cache.commit_partial(digest).map_err(DockerError::Cache)
+}
+
+/// Compression of a layer, from its media type
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+fn layer_compression(media_type: &str) -> Result<Compression> {
+    match media_type {
+        LAYER_TAR | DOCKER_LAYER_TAR => Ok(Compression::None),
+        LAYER_GZIP | DOCKER_LAYER_GZIP => Ok(Compression::Gzip),
+        LAYER_ZSTD => Ok(Compression::Zstd),
+        _ => {
+            // Includes foreign and non-distributable layers, which are not in the registry
+            error!(target:"docker_layer", "unsupported media type {}", media_type);
+            Err(DockerError::UnsupportedMediaType(String::from(media_type)))
+        }
+    }
+}
+
+/// Returns the uncompressed tar stream of a layer blob
+pub fn decompress<'a, R: BufRead + 'a>(media_type: &str, blob: R) -> Result<Box<dyn Read + 'a>> {
+    match layer_compression(media_type)? {
+        Compression::None => Ok(Box::new(blob)),
+        Compression::Gzip => Ok(Box::new(GzDecoder::new(blob))),
+        Compression::Zstd => match zstd::Decoder::with_buffer(blob) {
+            Ok(d) => Ok(Box::new(d)),
+            Err(e) => Err(DockerError::Unpack(LayerError::IOError(e))),
+        },
+    }
+}
+
+fn apply_layer(
+    cache: &ImageCache,
+    layer: &Layer,
+    output: &Path,
+    options: &ExtractOptions,
+    report: &mut ExtractReport,
+) -> Result<()> {
+    let blob_path = cache.blob_path(&layer.digest).map_err(DockerError::Cache)?;
+
+    let blob = match File::open(blob_path) {
+        Ok(f) => f,
+        Err(e) => return Err(DockerError::Cache(CacheError::IOError(e))),
     };
 
-    let bytes = match layer_req.bytes() {
-        Ok(b) => b,
-        Err(_) => return Err(DockerError::Parse),
+    let tar = decompress(&layer.media_type, BufReader::new(blob))?;
+
+    match layer::apply(tar, output, options, report) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(DockerError::Unpack(e)),
+    }
+}
+
+/// Images are cached per platform, the same tag may be pulled for several
+fn cache_key(reference: &ImageReference, platform: &Platform) -> String {
+    format!("{} {}", reference, platform)
+}
+
+/// Fetches an image from the registry into the cache, returns its manifest digest
+fn pull_into(
+    cache: &ImageCache,
+    reference: &ImageReference,
+    platform: &Platform,
+    options: &PullOptions,
+) -> Result<String> {
+    info!(target:"docker", "pulling {}", reference);
+
+    let registry = Registry::connect(reference, options)?;
+
+    let (digest, manifest_data) = resolve_manifest(&registry, reference, platform)?;
+
+    info!(target:"docker_manifest_digest", "{}", digest);
+
+    let manifest_layers = parse_image_manifest(&manifest_data)?;
+
+    // Fail before downloading anything if a layer cannot be applied
+    for layer in &manifest_layers.layers {
+        layer_compression(&layer.media_type)?;
+    }
+
+    if !cache.has_blob(&manifest_layers.config.digest) {
+        info!(target:"docker_config_digest", "{}", manifest_layers.config.digest);
+
+        download_blob(&registry, &manifest_layers.config.digest, None, cache)?;
+    }
+
+    for layer in &manifest_layers.layers {
+        if cache.has_blob(&layer.digest) {
+            info!(target:"docker_layer_digest", "{} already cached", layer.digest);
+            continue;
+        }
+
+        info!(target:"docker_layer_digest", "{}", layer.digest);
+
+        download_blob(&registry, &layer.digest, layer.size, cache)?;
+    }
+
+    // The manifest is stored last so a cached manifest always has its layers
+    cache
+        .write_blob(&digest, &manifest_data)
+        .map_err(DockerError::Cache)?;
+
+    cache
+        .tag(&cache_key(reference, platform), &digest)
+        .map_err(DockerError::Cache)?;
+
+    info!(target:"docker", "{} pulled for {}", reference, platform);
+
+    Ok(digest)
+}
+
+fn parse_reference(image: &str) -> Result<ImageReference> {
+    match ImageReference::parse(image) {
+        Ok(r) => Ok(r),
+        Err(e) => {
+            error!(target:"docker", "invalid image {}: {:?}", image, e);
+            Err(DockerError::InvalidImage(e))
+        }
+    }
+}
+
+pub fn pull(image: &str, options: &PullOptions) -> Result<String> {
+    let reference = parse_reference(image)?;
+
+    let platform = options.platform.clone().unwrap_or_else(Platform::host);
+
+    let cache = ImageCache::open().map_err(DockerError::Cache)?;
+
+    pull_into(&cache, &reference, &platform, options)
+}
+
+/// Returns the runtime settings of the image, empty if its config is not cached
+fn read_config(cache: &ImageCache, digest: &str) -> Result<ImageConfig> {
+    if !cache.has_blob(digest) {
+        // Images cached by older versions do not have their config
+        warn!(target:"docker_config_digest", "{} is not cached", digest);
+        return Ok(ImageConfig::default());
+    }
+
+    let data = cache.read_blob(digest).map_err(DockerError::Cache)?;
+
+    parse_config(&data)
+}
+
+/// Returns the runtime settings found in an image config blob
+pub fn parse_config(data: &[u8]) -> Result<ImageConfig> {
+    match serde_json::from_slice::<ConfigFile>(data) {
+        Ok(c) => Ok(c.config.unwrap_or_default()),
+        Err(_) => Err(DockerError::Parse),
+    }
+}
+
+/// Extracts the image into output and returns its runtime settings
+pub fn download(image: &str, output: &Path, options: &PullOptions) -> Result<ImageConfig> {
+    let reference = parse_reference(image)?;
+
+    let platform = options.platform.clone().unwrap_or_else(Platform::host);
+
+    let cache = ImageCache::open().map_err(DockerError::Cache)?;
+
+    let cached = cache
+        .resolve(&cache_key(&reference, &platform))
+        .map_err(DockerError::Cache)?;
+
+    // A tag may have been moved since it was cached, so the registry is asked
+    // again, only the missing blobs are downloaded. A digest never changes.
+    let digest = match cached {
+        Some(d) if options.offline || reference.digest.is_some() => {
+            info!(target:"docker", "{} found in cache", reference);
+            d
+        }
+        None if options.offline => {
+            error!(target:"docker", "{} is not cached", reference);
+            return Err(DockerError::NotCached(reference.to_string()));
+        }
+        cached => match (pull_into(&cache, &reference, &platform, options), cached) {
+            (Ok(d), _) => d,
+            // The registry cannot be reached
+            (Err(DockerError::RequestFailed), Some(d)) => {
+                warn!(target:"docker", "registry unreachable, using the cached {}", reference);
+                d
+            }
+            (Err(e), _) => return Err(e),
+        },
     };
 
-    let tar = GzDecoder::new(&bytes[..]);
+    info!(target:"docker_manifest_digest", "{}", digest);
 
-    let mut archive = Archive::new(tar);
+    let manifest_data = cache.read_blob(&digest).map_err(DockerError::Cache)?;
 
-    match archive.unpack(output) {
-        Ok(()) => Ok(()),
-        Err(_) => Err(DockerError::Unpack),
+    let manifest_layers = parse_image_manifest(&manifest_data)?;
+
+    let mut report = ExtractReport::default();
+
+    // Layers are ordered from the base to the top of the image
+    for layer in &manifest_layers.layers {
+        info!(target:"docker_layer_digest", "{}", layer.digest);
+
+        apply_layer(&cache, layer, output, &options.extract, &mut report)?;
+    }
+
+    report.log();
+
+    info!(target:"docker", "{} extracted", reference);
+
+    read_config(&cache, &manifest_layers.config.digest)
+}
+
+/// Returns the cached images as (reference, manifest digest, size in bytes)
+pub fn list() -> Result<Vec<(String, String, u64)>> {
+    let cache = ImageCache::open().map_err(DockerError::Cache)?;
+
+    let mut images: Vec<(String, String, u64)> = Vec::new();
+
+    for (reference, digest) in cache.tags().map_err(DockerError::Cache)? {
+        let manifest_data = cache.read_blob(&digest).map_err(DockerError::Cache)?;
+
+        let manifest_layers = parse_image_manifest(&manifest_data)?;
+
+        let mut size: u64 = manifest_data.len() as u64;
+
+        for layer in &manifest_layers.layers {
+            size += cache.blob_size(&layer.digest).unwrap_or(0);
+        }
+
+        images.push((reference, digest, size));
+    }
+
+    Ok(images)
+}
+
+/// Digests of the config and layers of a manifest, none if it is not one
+fn manifest_references(data: &[u8]) -> Vec<String> {
+    match parse_image_manifest(data) {
+        Ok(manifest) => std::iter::once(manifest.config.digest)
+            .chain(manifest.layers.into_iter().map(|layer| layer.digest))
+            .collect(),
+        Err(_) => Vec::new(),
     }
 }
 
-pub fn download(image: &str, output: &Path) -> Result<()> {
-    let parts: Vec<&str> = image.split(":").collect();
+/// Removes the blobs no cached reference points to, or everything if all is set.
+/// Returns the number of removed blobs and the freed bytes.
+pub fn prune(all: bool) -> Result<(usize, u64)> {
+    let cache = ImageCache::open().map_err(DockerError::Cache)?;
+
+    // Emptying the cache is asked for explicitly, recent blobs go too
+    let grace = if all {
+        Duration::ZERO
+    } else {
+        image_cache::PRUNE_GRACE
+    };
+
+    cache
+        .prune(all, grace, manifest_references)
+        .map_err(DockerError::Cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABC: &str = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
 
-    if parts.len() != 2 {
-        return Err(DockerError::InvalidImage);
+    #[test]
+    fn matching_digest() {
+        assert!(verify_digest(b"abc", ABC).is_ok());
+        assert!(verify_digest(b"abc", &ABC.to_uppercase().replace("SHA256", "sha256")).is_ok());
+        assert!(verify_hash(Sha256::digest(b"abc").as_slice(), ABC).is_ok());
     }
-    let (image_name, image_version) = (parts[0], parts[1]);
 
-    info!(target:"docker", "downloading {}:{}", image_name, image_version);
+    #[test]
+    fn mismatching_digest() {
+        assert!(matches!(
+            verify_digest(b"abd", ABC),
+            Err(DockerError::DigestMismatch(d)) if d == ABC
+        ));
+        assert!(matches!(
+            verify_digest(b"abc", "sha256:"),
+            Err(DockerError::DigestMismatch(_))
+        ));
+    }
 
-    let token = get_auth_token(image_name)?;
+    #[test]
+    fn unsupported_digest() {
+        for digest in [
+            "sha512:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    verify_digest(b"abc", digest),
+                    Err(DockerError::UnsupportedDigest(_))
+                ),
+                "{}",
+                digest
+            );
+        }
+    }
 
-    let manifest = get_manifest(&token, image_name, image_version)?;
+    #[test]
+    fn bearer_challenge() {
+        let (scheme, params) = parse_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull""#,
+        );
 
-    info!(target:"docker_manifest_digest", "{}", manifest.digest);
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/alpine:pull");
+    }
 
-    let layer = get_layer(&token, image_name, &manifest)?;
+    #[test]
+    fn quoted_commas_and_escapes() {
+        let (_, params) = parse_challenge(r#"Bearer scope="repository:a:pull,push", Realm="a\"b""#);
 
-    info!(target:"docker_layer_digest", "{}", layer.digest);
+        assert_eq!(params["scope"], "repository:a:pull,push");
+        assert_eq!(params["realm"], "a\"b");
+    }
 
-    download_layer(&token, image_name, &layer, output)?;
+    #[test]
+    fn unquoted_values() {
+        let (scheme, params) = parse_challenge("Basic realm=registry,charset=UTF-8");
 
-    info!(target:"docker", "{}:{} downloaded", image_name, image_version);
+        assert_eq!(scheme, "basic");
+        assert_eq!(params["realm"], "registry");
+        assert_eq!(params["charset"], "UTF-8");
+    }
 
-    Ok(())
+    #[test]
+    fn scheme_only() {
+        let (scheme, params) = parse_challenge("Basic");
+
+        assert_eq!(scheme, "basic");
+        assert!(params.is_empty());
+    }
 }
@@ -0,0 +1,440 @@
+use log::{error, info, warn};
+use nix::fcntl::{flock, FlockArg};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    fs::{self, File},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, SystemTime},
+};
+
+const DEFAULT_CACHE_DIR: &str = "/var/cache/moulinette";
+const CACHE_DIR_ENV: &str = "MOULINETTE_CACHE_DIR";
+
+/// Blobs younger than this may belong to a pull which did not tag them yet
+pub const PRUNE_GRACE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+pub enum CacheError {
+    IOError(std::io::Error),
+    InvalidDigest(String),
+    Parse,
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::IOError(e) => write!(f, "{}", e),
+            CacheError::InvalidDigest(digest) => write!(f, "invalid digest {}", digest),
+            CacheError::Parse => write!(f, "cannot parse the index"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, CacheError>;
+
+/// Content-addressed store of blobs and of the image references pointing to them
+///
+/// Layout:
+/// - blobs/sha256/<hex>: manifests, configs and layers keyed by digest
+/// - blobs/sha256/<hex>.partial: blobs being downloaded, locked by the downloading run
+/// - index.json: image reference to manifest digest
+/// - index.lock: held while index.json is updated
+pub struct ImageCache {
+    root: PathBuf,
+}
+
+impl ImageCache {
+    pub fn open() -> Result<ImageCache> {
+        let root = match env::var_os(CACHE_DIR_ENV) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(DEFAULT_CACHE_DIR),
+        };
+
+        ImageCache::at(root)
+    }
+
+    fn at(root: PathBuf) -> Result<ImageCache> {
+        if let Err(e) = fs::create_dir_all(root.join("blobs").join("sha256")) {
+            error!(target:"cache", "cannot create {:?}", root);
+            return Err(CacheError::IOError(e));
+        }
+
+        info!(target:"cache", "using {:?}", root);
+
+        Ok(ImageCache { root })
+    }
+
+    pub fn blob_path(&self, digest: &str) -> Result<PathBuf> {
+        let hex = match digest.strip_prefix("sha256:") {
+            Some(h) => h,
+            None => return Err(CacheError::InvalidDigest(String::from(digest))),
+        };
+
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(CacheError::InvalidDigest(String::from(digest)));
+        }
+
+        Ok(self.root.join("blobs").join("sha256").join(hex))
+    }
+
+    pub fn has_blob(&self, digest: &str) -> bool {
+        match self.blob_path(digest) {
+            Ok(p) => p.is_file(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn read_blob(&self, digest: &str) -> Result<Vec<u8>> {
+        fs::read(self.blob_path(digest)?).map_err(CacheError::IOError)
+    }
+
+    /// Stores an already verified blob
+    pub fn write_blob(&self, digest: &str, data: &[u8]) -> Result<()> {
+        let path = self.blob_path(digest)?;
+
+        write_atomic(&path, data)?;
+
+        info!(target:"cache_blob", "stored {}", digest);
+
+        Ok(())
+    }
+
+    /// Returns where a blob is written while it is being downloaded
+    pub fn partial_path(&self, digest: &str) -> Result<PathBuf> {
+        Ok(self.blob_path(digest)?.with_extension("partial"))
+    }
+
+    /// Moves a downloaded and verified blob to its final place
+    pub fn commit_partial(&self, digest: &str) -> Result<()> {
+        let path = self.blob_path(digest)?;
+
+        if let Err(e) = fs::rename(path.with_extension("partial"), &path) {
+            error!(target:"cache", "cannot store {}", digest);
+            return Err(CacheError::IOError(e));
+        }
+
+        info!(target:"cache_blob", "stored {}", digest);
+
+        Ok(())
+    }
+
+    pub fn remove_blob(&self, digest: &str) -> Result<()> {
+        fs::remove_file(self.blob_path(digest)?).map_err(CacheError::IOError)
+    }
+
+    /// Returns the digests of every stored blob
+    pub fn blobs(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(self.root.join("blobs").join("sha256")) {
+            Ok(e) => e,
+            Err(e) => return Err(CacheError::IOError(e)),
+        };
+
+        let mut digests: Vec<String> = Vec::new();
+
+        for entry in entries {
+            let entry = entry.map_err(CacheError::IOError)?;
+
+            // Skip the temporary files of interrupted writes
+            if let Some(name) = entry.file_name().to_str() {
+                if name.chars().all(|c| c.is_ascii_hexdigit()) {
+                    digests.push(format!("sha256:{}", name));
+                }
+            }
+        }
+
+        Ok(digests)
+    }
+
+    pub fn blob_size(&self, digest: &str) -> Result<u64> {
+        match fs::metadata(self.blob_path(digest)?) {
+            Ok(m) => Ok(m.len()),
+            Err(e) => Err(CacheError::IOError(e)),
+        }
+    }
+
+    /// Returns every cached image reference and the digest of its manifest
+    pub fn tags(&self) -> Result<BTreeMap<String, String>> {
+        let data = match fs::read(self.root.join("index.json")) {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(CacheError::IOError(e)),
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(t) => Ok(t),
+            Err(_) => Err(CacheError::Parse),
+        }
+    }
+
+    fn write_tags(&self, tags: &BTreeMap<String, String>) -> Result<()> {
+        let data = match serde_json::to_vec_pretty(tags) {
+            Ok(d) => d,
+            Err(_) => return Err(CacheError::Parse),
+        };
+
+        write_atomic(&self.root.join("index.json"), &data)
+    }
+
+    pub fn resolve(&self, reference: &str) -> Result<Option<String>> {
+        let mut tags = self.tags()?;
+
+        Ok(tags.remove(reference))
+    }
+
+    /// index.json is replaced on each write, so a separate file is locked
+    fn lock_index(&self) -> Result<File> {
+        let path = self.root.join("index.lock");
+
+        let file = match File::options().create(true).append(true).open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!(target:"cache", "cannot open {:?}", path);
+                return Err(CacheError::IOError(e));
+            }
+        };
+
+        lock_exclusive(&file).map_err(CacheError::IOError)?;
+
+        Ok(file)
+    }
+
+    pub fn tag(&self, reference: &str, digest: &str) -> Result<()> {
+        let _lock = self.lock_index()?;
+
+        let mut tags = self.tags()?;
+
+        tags.insert(String::from(reference), String::from(digest));
+
+        self.write_tags(&tags)
+    }
+
+    /// Returns true if the blob was modified less than grace ago
+    fn is_recent(&self, digest: &str, grace: Duration) -> bool {
+        let modified = fs::metadata(self.blob_path(digest).unwrap_or_default())
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+
+        match SystemTime::now().duration_since(modified) {
+            Ok(age) => age < grace,
+            // Modified in the future
+            Err(_) => true,
+        }
+    }
+
+    /// Removes the blobs no reference reaches, or every reference and blob if
+    /// all is set. references returns the digests a manifest points to. Blobs
+    /// younger than grace are kept. Returns the number of removed blobs and the
+    /// freed bytes.
+    pub fn prune<F>(&self, all: bool, grace: Duration, references: F) -> Result<(usize, u64)>
+    where
+        F: Fn(&[u8]) -> Vec<String>,
+    {
+        // Tags cannot change while we compute what they reach
+        let _lock = self.lock_index()?;
+
+        let mut tags = if all { BTreeMap::new() } else { self.tags()? };
+        let mut reachable: HashSet<String> = HashSet::new();
+
+        tags.retain(|reference, digest| match self.read_blob(digest) {
+            Ok(data) => {
+                reachable.extend(references(&data));
+                reachable.insert(digest.clone());
+                true
+            }
+            // A reference without manifest is useless
+            Err(e) => {
+                warn!(target:"cache_prune", "{} is broken: {:?}", reference, e);
+                false
+            }
+        });
+
+        self.write_tags(&tags)?;
+
+        let mut removed: usize = 0;
+        let mut freed: u64 = 0;
+
+        for digest in self.blobs()? {
+            if reachable.contains(&digest) || self.is_recent(&digest, grace) {
+                continue;
+            }
+
+            freed += self.blob_size(&digest).unwrap_or(0);
+
+            self.remove_blob(&digest)?;
+
+            info!(target:"cache_prune", "removed {}", digest);
+
+            removed += 1;
+        }
+
+        Ok((removed, freed))
+    }
+}
+
+/// Waits until no other run holds a lock on file, the lock is released when
+/// file is closed
+pub fn lock_exclusive(file: &File) -> std::io::Result<()> {
+    flock(file.as_raw_fd(), FlockArg::LockExclusive).map_err(std::io::Error::from)
+}
+
+/// Returns true if path still leads to file, it may have been renamed or
+/// removed by the run holding the lock before us
+pub fn is_same_file(file: &File, path: &Path) -> bool {
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Writes a file through a temporary one so concurrent runs never see partial data
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension(format!("tmp{}", process::id()));
+
+    if let Err(e) = fs::write(&tmp_path, data) {
+        error!(target:"cache", "cannot write {:?}", tmp_path);
+        return Err(CacheError::IOError(e));
+    }
+
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        error!(target:"cache", "cannot rename {:?}", tmp_path);
+        let _ = fs::remove_file(&tmp_path);
+        return Err(CacheError::IOError(e));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn digest(c: char) -> String {
+        format!("sha256:{}", c.to_string().repeat(64))
+    }
+
+    fn cache() -> (TempDir, ImageCache) {
+        let dir = TempDir::new("moulinette").unwrap();
+        let cache = ImageCache::at(dir.path().to_path_buf()).unwrap();
+
+        (dir, cache)
+    }
+
+    /// Manifests of the tests list the digests they reach, one per line
+    fn references(data: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(data)
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn blob_paths() {
+        let (dir, cache) = cache();
+
+        assert_eq!(
+            cache.blob_path(&digest('a')).unwrap(),
+            dir.path().join("blobs/sha256").join("a".repeat(64))
+        );
+
+        for invalid in [
+            "",
+            "sha256:",
+            "sha512:abcd",
+            "sha256:../index",
+            "sha256:ab/cd",
+        ] {
+            assert!(
+                matches!(cache.blob_path(invalid), Err(CacheError::InvalidDigest(_))),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn tags() {
+        let (_dir, cache) = cache();
+
+        assert!(cache.tags().unwrap().is_empty());
+
+        cache
+            .tag("docker.io/library/alpine:3", &digest('a'))
+            .unwrap();
+        cache
+            .tag("docker.io/library/alpine:latest", &digest('b'))
+            .unwrap();
+
+        // A moved tag replaces the previous digest
+        cache
+            .tag("docker.io/library/alpine:3", &digest('c'))
+            .unwrap();
+
+        assert_eq!(
+            cache.resolve("docker.io/library/alpine:3").unwrap(),
+            Some(digest('c'))
+        );
+        assert_eq!(
+            cache.resolve("docker.io/library/alpine:latest").unwrap(),
+            Some(digest('b'))
+        );
+        assert_eq!(
+            cache.resolve("docker.io/library/busybox:latest").unwrap(),
+            None
+        );
+        assert_eq!(cache.tags().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn prune_keeps_reachable_blobs() {
+        let (_dir, cache) = cache();
+
+        let manifest = format!("{}\n{}", digest('b'), digest('c'));
+
+        cache.write_blob(&digest('a'), manifest.as_bytes()).unwrap();
+        cache.write_blob(&digest('b'), b"config").unwrap();
+        cache.write_blob(&digest('c'), b"layer").unwrap();
+        cache.write_blob(&digest('d'), b"orphan").unwrap();
+        cache.tag("img:latest", &digest('a')).unwrap();
+        cache.tag("broken:latest", &digest('e')).unwrap();
+
+        let pruned = cache.prune(false, Duration::ZERO, references).unwrap();
+
+        assert_eq!(pruned, (1, 6));
+        assert!(!cache.has_blob(&digest('d')));
+        assert!(['a', 'b', 'c'].iter().all(|c| cache.has_blob(&digest(*c))));
+
+        // The reference without manifest is dropped
+        assert_eq!(cache.tags().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn prune_keeps_recent_blobs() {
+        let (_dir, cache) = cache();
+
+        cache.write_blob(&digest('d'), b"not tagged yet").unwrap();
+
+        let pruned = cache.prune(false, PRUNE_GRACE, references).unwrap();
+
+        assert_eq!(pruned, (0, 0));
+        assert!(cache.has_blob(&digest('d')));
+    }
+
+    #[test]
+    fn prune_all() {
+        let (_dir, cache) = cache();
+
+        cache.write_blob(&digest('a'), b"").unwrap();
+        cache.write_blob(&digest('b'), b"").unwrap();
+        cache.tag("img:latest", &digest('a')).unwrap();
+
+        let pruned = cache.prune(true, Duration::ZERO, references).unwrap();
+
+        assert_eq!(pruned.0, 2);
+        assert!(cache.tags().unwrap().is_empty());
+        assert!(cache.blobs().unwrap().is_empty());
+    }
+}
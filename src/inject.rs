@@ -0,0 +1,225 @@
+use log::{error, info};
+use serde::Deserialize;
+use std::{
+    fs,
+    os::unix::fs::{lchown, symlink, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::layer::{self, LayerError};
+
+#[derive(Debug)]
+pub enum InjectError {
+    IOError(std::io::Error),
+    Parse,
+    InvalidEntry(String),
+    Layer(LayerError),
+}
+
+impl std::fmt::Display for InjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InjectError::IOError(e) => write!(f, "{}", e),
+            InjectError::Parse => write!(f, "cannot parse the manifest"),
+            InjectError::InvalidEntry(entry) => write!(f, "invalid copy {}", entry),
+            InjectError::Layer(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, InjectError>;
+
+/// A host file or directory copied into the rootfs before the program starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyEntry {
+    pub source: PathBuf,
+    /// Absolute path in the sandbox
+    pub target: PathBuf,
+    /// Permissions of the target itself, the content of a directory keeps its own
+    pub mode: Option<u32>,
+    /// Owner of everything copied
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// An entry of a copy manifest, sources are relative to the manifest
+#[derive(Deserialize)]
+struct ManifestEntry {
+    source: PathBuf,
+    target: PathBuf,
+    /// Octal string, as "0755"
+    mode: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+fn parse_mode(mode: &str) -> Option<u32> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(m) if m <= 0o7777 => Some(m),
+        _ => None,
+    }
+}
+
+impl CopyEntry {
+    /// Parses src:dst[:mode[:uid[:gid]]], mode being octal
+    pub fn parse(spec: &str) -> Option<CopyEntry> {
+        let parts: Vec<&str> = spec.split(':').collect();
+
+        if parts.len() < 2 || parts.len() > 5 {
+            return None;
+        }
+
+        let optional = |i: usize| parts.get(i).filter(|p| !p.is_empty());
+
+        let mode = match optional(2) {
+            Some(m) => Some(parse_mode(m)?),
+            None => None,
+        };
+
+        let uid = match optional(3) {
+            Some(u) => Some(u.parse::<u32>().ok()?),
+            None => None,
+        };
+
+        let gid = match optional(4) {
+            Some(g) => Some(g.parse::<u32>().ok()?),
+            None => None,
+        };
+
+        if parts[0].is_empty() || !parts[1].starts_with('/') {
+            return None;
+        }
+
+        Some(CopyEntry {
+            source: PathBuf::from(parts[0]),
+            target: PathBuf::from(parts[1]),
+            mode,
+            uid,
+            gid,
+        })
+    }
+}
+
+/// Reads a JSON array of {source, target, mode, uid, gid}
+pub fn load_manifest(path: &Path) -> Result<Vec<CopyEntry>> {
+    let data = fs::read(path).map_err(InjectError::IOError)?;
+
+    let entries = match serde_json::from_slice::<Vec<ManifestEntry>>(&data) {
+        Ok(e) => e,
+        Err(_) => {
+            error!(target:"inject", "cannot parse {:?}", path);
+            return Err(InjectError::Parse);
+        }
+    };
+
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut copies: Vec<CopyEntry> = Vec::new();
+
+    for entry in entries {
+        let mode = match entry.mode.as_deref().map(parse_mode) {
+            Some(None) => return Err(InjectError::InvalidEntry(format!("{:?}", entry.mode))),
+            Some(m) => m,
+            None => None,
+        };
+
+        if !entry.target.is_absolute() {
+            return Err(InjectError::InvalidEntry(format!("{:?}", entry.target)));
+        }
+
+        copies.push(CopyEntry {
+            source: base.join(entry.source),
+            target: entry.target,
+            mode,
+            uid: entry.uid,
+            gid: entry.gid,
+        });
+    }
+
+    Ok(copies)
+}
+
+/// Removes whatever the image has at dst unless both are directories
+fn make_room(dst: &Path, is_dir: bool) -> Result<()> {
+    let result = match fs::symlink_metadata(dst) {
+        Ok(m) if m.is_dir() && is_dir => Ok(()),
+        Ok(m) if m.is_dir() => fs::remove_dir_all(dst),
+        Ok(_) => fs::remove_file(dst),
+        Err(_) => Ok(()),
+    };
+
+    result.map_err(InjectError::IOError)
+}
+
+/// Copies src to dst, dst being free of symlinks up to its parent
+fn copy_tree(src: &Path, dst: &Path, entry: &CopyEntry) -> Result<()> {
+    let metadata = fs::symlink_metadata(src).map_err(InjectError::IOError)?;
+
+    make_room(dst, metadata.is_dir())?;
+
+    let result = if metadata.is_dir() {
+        fs::create_dir_all(dst).and_then(|_| fs::set_permissions(dst, metadata.permissions()))
+    } else if metadata.file_type().is_symlink() {
+        fs::read_link(src).and_then(|target| symlink(target, dst))
+    } else {
+        fs::copy(src, dst).map(|_| ())
+    };
+
+    if let Err(e) = result {
+        error!(target:"inject", "cannot copy {:?}", src);
+        return Err(InjectError::IOError(e));
+    }
+
+    if entry.uid.is_some() || entry.gid.is_some() {
+        lchown(dst, entry.uid, entry.gid).map_err(InjectError::IOError)?;
+    }
+
+    if metadata.is_dir() {
+        for child in fs::read_dir(src).map_err(InjectError::IOError)? {
+            let child = child.map_err(InjectError::IOError)?;
+            copy_tree(&child.path(), &dst.join(child.file_name()), entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the entries into rootfs, their targets never leave it
+pub fn copy_into(rootfs: &Path, entries: &[CopyEntry]) -> Result<()> {
+    for entry in entries {
+        let name = match entry.target.file_name() {
+            Some(n) => n,
+            None => return Err(InjectError::InvalidEntry(format!("{:?}", entry.target))),
+        };
+
+        let parent = entry.target.parent().unwrap_or_else(|| Path::new("/"));
+
+        // Symlinks of the image are followed as if rootfs was /
+        let parent =
+            rootfs.join(layer::resolve_in_root(rootfs, parent).map_err(InjectError::Layer)?);
+
+        if let Err(e) = fs::create_dir_all(&parent) {
+            error!(target:"inject", "cannot create {:?}", parent);
+            return Err(InjectError::IOError(e));
+        }
+
+        let dst = parent.join(name);
+
+        copy_tree(&entry.source, &dst, entry)?;
+
+        // chmod would follow a symlink out of the rootfs
+        let is_symlink = fs::symlink_metadata(&dst)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+
+        if let Some(mode) = entry.mode.filter(|_| !is_symlink) {
+            if let Err(e) = fs::set_permissions(&dst, fs::Permissions::from_mode(mode)) {
+                return Err(InjectError::IOError(e));
+            }
+        }
+
+        info!(target:"inject", "{:?} copied to {:?}", entry.source, entry.target);
+    }
+
+    Ok(())
+}
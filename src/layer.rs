@@ -0,0 +1,597 @@
+use log::{error, info, warn};
+use std::{
+    collections::{HashSet, VecDeque},
+    ffi::OsString,
+    fs,
+    io::Read,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, EntryType};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Same limit as the kernel when following symlinks
+const MAX_SYMLINKS: u32 = 40;
+
+const SETID_BITS: u32 = 0o6000;
+
+#[derive(Debug)]
+pub enum LayerError {
+    IOError(std::io::Error),
+    InvalidPath(PathBuf),
+    SymlinkLoop(PathBuf),
+}
+
+impl std::fmt::Display for LayerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LayerError::IOError(e) => write!(f, "{}", e),
+            LayerError::InvalidPath(path) => write!(f, "invalid path {:?}", path),
+            LayerError::SymlinkLoop(path) => write!(f, "too many symlinks in {:?}", path),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, LayerError>;
+
+/// How untrusted layers are extracted
+#[derive(Debug, Default, Clone)]
+pub struct ExtractOptions {
+    /// Removes the setuid and setgid bits of the extracted files
+    pub strip_setid: bool,
+}
+
+/// What the extraction refused to apply as is
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    /// Entries which were not extracted, with the reason
+    pub skipped: Vec<(PathBuf, &'static str)>,
+    /// Files whose setuid or setgid bit was removed
+    pub stripped: Vec<PathBuf>,
+}
+
+impl ExtractReport {
+    fn skip(&mut self, path: &Path, reason: &'static str) {
+        warn!(target:"layer_skip", "{:?}: {}", path, reason);
+        self.skipped.push((PathBuf::from(path), reason));
+    }
+
+    pub fn log(&self) {
+        info!(target:"layer_report", "{} entries skipped, {} setid bits stripped",
+            self.skipped.len(), self.stripped.len());
+    }
+}
+
+/// Returns the path of an entry relative to the rootfs, None if it escapes it
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => normalized.push(c),
+            Component::RootDir | Component::CurDir => continue,
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(normalized)
+}
+
+/// Resolves a path as if rootfs were /, following the symlinks of the rootfs
+/// without ever leaving it. Returns a path relative to rootfs without symlinks.
+pub fn resolve_in_root(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    let mut pending: VecDeque<OsString> = path
+        .components()
+        .map(|c| c.as_os_str().to_os_string())
+        .collect();
+    let mut links: u32 = 0;
+
+    while let Some(component) = pending.pop_front() {
+        if component == "/" || component == "." {
+            continue;
+        }
+
+        // The root is its own parent
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&component);
+
+        let is_symlink = fs::symlink_metadata(rootfs.join(&candidate))
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+
+        if !is_symlink {
+            resolved = candidate;
+            continue;
+        }
+
+        links += 1;
+
+        if links > MAX_SYMLINKS {
+            return Err(LayerError::SymlinkLoop(PathBuf::from(path)));
+        }
+
+        let target = fs::read_link(rootfs.join(&candidate)).map_err(LayerError::IOError)?;
+
+        // Absolute targets are relative to the rootfs, not to the host
+        if target.has_root() {
+            resolved = PathBuf::new();
+        }
+
+        for c in target.components().rev() {
+            pending.push_front(c.as_os_str().to_os_string());
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Resolves the parent of a path in the rootfs, the last component is kept as is
+fn resolve_parent(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    let parent = resolve_in_root(rootfs, path.parent().unwrap_or_else(|| Path::new("")))?;
+
+    match path.file_name() {
+        Some(name) => Ok(parent.join(name)),
+        None => Ok(parent),
+    }
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes the content of a directory coming from the lower layers. The
+/// directories this layer merged before its opaque marker are cleared too.
+fn clear_directory(rootfs: &Path, dir: &Path, unpacked: &HashSet<PathBuf>) -> Result<()> {
+    let entries = match fs::read_dir(rootfs.join(dir)) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(LayerError::IOError(e)),
+    };
+
+    for entry in entries {
+        let entry = entry.map_err(LayerError::IOError)?;
+        let relative = dir.join(entry.file_name());
+
+        if unpacked.contains(&relative) {
+            let is_dir = entry.file_type().map_err(LayerError::IOError)?.is_dir();
+
+            if is_dir {
+                clear_directory(rootfs, &relative, unpacked)?;
+            }
+
+            continue;
+        }
+
+        if let Err(e) = remove_path(&entry.path()) {
+            error!(target:"layer_whiteout", "{:?}: {}", relative, e);
+            return Err(LayerError::IOError(e));
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the setuid and setgid bits of an extracted file
+fn strip_setid(dst: &Path) -> Result<bool> {
+    let metadata = fs::symlink_metadata(dst).map_err(LayerError::IOError)?;
+
+    let mode = metadata.permissions().mode();
+
+    if !metadata.is_file() || mode & SETID_BITS == 0 {
+        return Ok(false);
+    }
+
+    fs::set_permissions(dst, fs::Permissions::from_mode(mode & !SETID_BITS))
+        .map_err(LayerError::IOError)?;
+
+    Ok(true)
+}
+
+/// Applies an uncompressed layer tarball on top of rootfs, honouring whiteouts.
+/// Every entry is confined to the rootfs and device nodes are never created.
+pub fn apply<R: Read>(
+    layer: R,
+    rootfs: &Path,
+    options: &ExtractOptions,
+    report: &mut ExtractReport,
+) -> Result<()> {
+    let mut archive = Archive::new(layer);
+
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_overwrite(true);
+
+    // Paths created by this layer, an opaque whiteout only hides lower layers
+    let mut unpacked: HashSet<PathBuf> = HashSet::new();
+
+    for entry in archive.entries().map_err(LayerError::IOError)? {
+        let mut entry = entry.map_err(LayerError::IOError)?;
+
+        let raw_path = entry.path().map_err(LayerError::IOError)?.into_owned();
+
+        let path = match normalize(&raw_path) {
+            Some(p) if p.as_os_str().is_empty() => continue,
+            Some(p) => p,
+            None => {
+                report.skip(&raw_path, "escapes the rootfs");
+                continue;
+            }
+        };
+
+        let entry_type = entry.header().entry_type();
+
+        if matches!(entry_type, EntryType::Char | EntryType::Block) {
+            report.skip(&path, "device node");
+            continue;
+        }
+
+        // Where the entry lands once the symlinks of the lower layers are followed
+        let resolved = resolve_parent(rootfs, &path)?;
+        let parent = resolved.parent().unwrap_or_else(|| Path::new(""));
+        let file_name = resolved.file_name().and_then(|n| n.to_str());
+
+        if file_name == Some(OPAQUE_WHITEOUT) {
+            info!(target:"layer_whiteout", "opaque {:?}", parent);
+            clear_directory(rootfs, parent, &unpacked)?;
+            continue;
+        }
+
+        if let Some(hidden) = file_name.and_then(|n| n.strip_prefix(WHITEOUT_PREFIX)) {
+            // .wh... would hide the parent of the rootfs and .wh.. the rootfs itself
+            if matches!(hidden, "" | "." | "..") || hidden.contains('/') {
+                report.skip(&path, "invalid whiteout");
+                continue;
+            }
+
+            // Resolved again so that only a path of the rootfs is ever removed
+            let hidden_path = resolve_parent(rootfs, &parent.join(hidden))?;
+
+            if hidden_path.file_name().is_none() {
+                report.skip(&path, "invalid whiteout");
+                continue;
+            }
+
+            info!(target:"layer_whiteout", "{:?}", hidden_path);
+
+            if let Err(e) = remove_path(&rootfs.join(&hidden_path)) {
+                error!(target:"layer_whiteout", "{:?}: {}", hidden_path, e);
+                return Err(LayerError::IOError(e));
+            }
+
+            continue;
+        }
+
+        let dst = rootfs.join(&resolved);
+
+        if let Err(e) = fs::create_dir_all(rootfs.join(parent)) {
+            error!(target:"layer", "{:?}: {}", parent, e);
+            return Err(LayerError::IOError(e));
+        }
+
+        // Only a directory is merged with what a lower layer left at this place,
+        // anything else is replaced so that no symlink is followed when writing
+        if let Ok(m) = fs::symlink_metadata(&dst) {
+            if !(m.is_dir() && entry_type.is_dir()) {
+                remove_path(&dst).map_err(LayerError::IOError)?;
+            }
+        }
+
+        let unpack_result = if entry_type.is_hard_link() {
+            let target = match entry.link_name().map_err(LayerError::IOError)? {
+                Some(t) => t.into_owned(),
+                None => return Err(LayerError::InvalidPath(path)),
+            };
+
+            // The target is resolved in the rootfs like any other path
+            let target = rootfs.join(resolve_parent(rootfs, &target)?);
+
+            match fs::symlink_metadata(&target) {
+                Ok(m) if !m.is_dir() => fs::hard_link(&target, &dst).map(|_| ()),
+                _ => {
+                    report.skip(&path, "hard link to a missing file");
+                    continue;
+                }
+            }
+        } else {
+            entry.unpack(&dst).map(|_| ())
+        };
+
+        if let Err(e) = unpack_result {
+            error!(target:"layer", "{:?}: {}", path, e);
+            return Err(LayerError::IOError(e));
+        }
+
+        if options.strip_setid && !entry_type.is_hard_link() && strip_setid(&dst)? {
+            info!(target:"layer_setid", "{:?} stripped", resolved);
+            report.stripped.push(resolved.clone());
+        }
+
+        unpacked.insert(resolved);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::unistd::{getgid, getuid};
+    use std::os::unix::fs::{symlink, MetadataExt};
+    use tar::{Builder, Header};
+    use tempdir::TempDir;
+
+    /// An entry with raw names, the tar builder refuses the malicious ones
+    fn entry(
+        name: &str,
+        entry_type: EntryType,
+        mode: u32,
+        link: &str,
+        data: &[u8],
+    ) -> (Header, Vec<u8>) {
+        let mut header = Header::new_gnu();
+
+        let old = header.as_old_mut();
+        old.name[..name.len()].copy_from_slice(name.as_bytes());
+        old.linkname[..link.len()].copy_from_slice(link.as_bytes());
+
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(data.len() as u64);
+        header.set_uid(getuid().as_raw() as u64);
+        header.set_gid(getgid().as_raw() as u64);
+        header.set_mtime(0);
+        header.set_cksum();
+
+        (header, data.to_vec())
+    }
+
+    fn file(name: &str, data: &[u8]) -> (Header, Vec<u8>) {
+        entry(name, EntryType::Regular, 0o644, "", data)
+    }
+
+    fn layer(entries: &[(Header, Vec<u8>)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        for (header, data) in entries {
+            builder.append(header, data.as_slice()).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    /// A rootfs next to a host file that no layer may reach
+    fn setup() -> (TempDir, PathBuf) {
+        let dir = TempDir::new("moulinette").unwrap();
+        let rootfs = dir.path().join("rootfs");
+
+        fs::create_dir(&rootfs).unwrap();
+        fs::write(dir.path().join("host"), "host").unwrap();
+
+        (dir, rootfs)
+    }
+
+    fn extract(rootfs: &Path, entries: &[(Header, Vec<u8>)], strip: bool) -> ExtractReport {
+        let mut report = ExtractReport::default();
+        let options = ExtractOptions { strip_setid: strip };
+
+        apply(layer(entries).as_slice(), rootfs, &options, &mut report).unwrap();
+
+        report
+    }
+
+    fn host_intact(dir: &TempDir) -> bool {
+        fs::read_to_string(dir.path().join("host")).ok().as_deref() == Some("host")
+    }
+
+    #[test]
+    fn parent_dir_entries_are_skipped() {
+        let (dir, rootfs) = setup();
+
+        let report = extract(
+            &rootfs,
+            &[file("../host", b"evil"), file("a/../../host", b"evil")],
+            false,
+        );
+
+        assert!(host_intact(&dir));
+        assert_eq!(report.skipped.len(), 2);
+    }
+
+    #[test]
+    fn absolute_paths_land_in_the_rootfs() {
+        let (dir, rootfs) = setup();
+
+        extract(&rootfs, &[file("/etc/hostname", b"box")], false);
+
+        assert!(host_intact(&dir));
+        assert_eq!(fs::read(rootfs.join("etc/hostname")).unwrap(), b"box");
+    }
+
+    #[test]
+    fn symlinks_are_followed_in_the_rootfs() {
+        let (dir, rootfs) = setup();
+        let host = dir.path().to_str().unwrap();
+
+        extract(
+            &rootfs,
+            &[
+                entry("abs", EntryType::Symlink, 0o777, host, b""),
+                file("abs/host", b"evil"),
+                entry("up", EntryType::Symlink, 0o777, "../../..", b""),
+                file("up/host", b"evil"),
+            ],
+            false,
+        );
+
+        assert!(host_intact(&dir));
+
+        // The absolute target is taken from the root of the rootfs
+        let inside = rootfs.join(host.trim_start_matches('/')).join("host");
+        assert_eq!(fs::read(inside).unwrap(), b"evil");
+        assert_eq!(fs::read(rootfs.join("host")).unwrap(), b"evil");
+    }
+
+    #[test]
+    fn entries_replace_lower_symlinks() {
+        let (dir, rootfs) = setup();
+
+        symlink(dir.path().join("host"), rootfs.join("file")).unwrap();
+
+        extract(&rootfs, &[file("file", b"evil")], false);
+
+        assert!(host_intact(&dir));
+        assert!(fs::symlink_metadata(rootfs.join("file")).unwrap().is_file());
+    }
+
+    #[test]
+    fn hard_links_stay_in_the_rootfs() {
+        let (dir, rootfs) = setup();
+        let host = dir.path().join("host");
+
+        let report = extract(
+            &rootfs,
+            &[
+                entry(
+                    "outside",
+                    EntryType::Link,
+                    0o644,
+                    host.to_str().unwrap(),
+                    b"",
+                ),
+                entry("parent", EntryType::Link, 0o644, "../host", b""),
+                file("etc/passwd", b"root"),
+                entry("passwd", EntryType::Link, 0o644, "/etc/passwd", b""),
+            ],
+            false,
+        );
+
+        assert!(host_intact(&dir));
+        assert_eq!(report.skipped.len(), 2);
+        assert!(!rootfs.join("outside").exists());
+        assert!(!rootfs.join("parent").exists());
+
+        let linked = fs::metadata(rootfs.join("passwd")).unwrap();
+        let target = fs::metadata(rootfs.join("etc/passwd")).unwrap();
+        assert_eq!(linked.ino(), target.ino());
+        assert_ne!(fs::metadata(host).unwrap().ino(), linked.ino());
+    }
+
+    #[test]
+    fn device_nodes_are_skipped() {
+        let (_dir, rootfs) = setup();
+
+        let report = extract(
+            &rootfs,
+            &[
+                entry("dev/null", EntryType::Char, 0o666, "", b""),
+                entry("dev/sda", EntryType::Block, 0o660, "", b""),
+            ],
+            false,
+        );
+
+        assert_eq!(report.skipped.len(), 2);
+        assert!(!rootfs.join("dev/null").exists());
+        assert!(!rootfs.join("dev/sda").exists());
+    }
+
+    #[test]
+    fn setid_bits_are_stripped() {
+        let entries = [
+            entry("su", EntryType::Regular, 0o4755, "", b""),
+            entry("wall", EntryType::Regular, 0o2755, "", b""),
+        ];
+        let mode = |rootfs: &Path, name: &str| {
+            fs::metadata(rootfs.join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+
+        let (_dir, rootfs) = setup();
+        let report = extract(&rootfs, &entries, true);
+
+        assert_eq!(mode(&rootfs, "su"), 0o755);
+        assert_eq!(mode(&rootfs, "wall"), 0o755);
+        assert_eq!(report.stripped.len(), 2);
+
+        let (_dir, rootfs) = setup();
+        let report = extract(&rootfs, &entries, false);
+
+        assert_eq!(mode(&rootfs, "su"), 0o4755);
+        assert_eq!(mode(&rootfs, "wall"), 0o2755);
+        assert!(report.stripped.is_empty());
+    }
+
+    #[test]
+    fn whiteouts_hide_lower_files() {
+        let (dir, rootfs) = setup();
+
+        fs::create_dir_all(rootfs.join("dir")).unwrap();
+        fs::write(rootfs.join("dir/a"), "a").unwrap();
+        fs::write(rootfs.join("dir/b"), "b").unwrap();
+
+        extract(&rootfs, &[file("dir/.wh.a", b"")], false);
+
+        assert!(!rootfs.join("dir/a").exists());
+        assert!(rootfs.join("dir/b").exists());
+        assert!(!rootfs.join("dir/.wh.a").exists());
+        assert!(host_intact(&dir));
+    }
+
+    #[test]
+    fn whiteouts_never_leave_the_rootfs() {
+        let (dir, rootfs) = setup();
+
+        fs::write(rootfs.join("keep"), "keep").unwrap();
+
+        let report = extract(
+            &rootfs,
+            &[file(".wh...", b""), file(".wh..", b""), file(".wh.", b"")],
+            false,
+        );
+
+        assert_eq!(report.skipped.len(), 3);
+        assert!(host_intact(&dir));
+        assert!(rootfs.join("keep").exists());
+    }
+
+    #[test]
+    fn opaque_whiteouts_keep_this_layer() {
+        let (dir, rootfs) = setup();
+
+        fs::create_dir_all(rootfs.join("dir/sub")).unwrap();
+        fs::write(rootfs.join("dir/old"), "old").unwrap();
+        fs::write(rootfs.join("dir/sub/old"), "old").unwrap();
+
+        extract(
+            &rootfs,
+            &[
+                entry("dir/sub/", EntryType::Directory, 0o755, "", b""),
+                file("dir/sub/new", b"new"),
+                file("dir/.wh..wh..opq", b""),
+                file("dir/newer", b"newer"),
+            ],
+            false,
+        );
+
+        assert!(!rootfs.join("dir/old").exists());
+        assert!(!rootfs.join("dir/sub/old").exists());
+        assert!(rootfs.join("dir/sub/new").exists());
+        assert!(rootfs.join("dir/newer").exists());
+        assert!(!rootfs.join("dir/.wh..wh..opq").exists());
+        assert!(host_intact(&dir));
+    }
+}
@@ -0,0 +1,518 @@
+use log::error;
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+use crate::cgroup::{CgroupV2Builder, CpuSet, IoMax, MAX_CPU_WEIGHT, MAX_IO_WEIGHT};
+use crate::units;
+
+/// Period of the cpu.max quota, in microseconds
+pub const CPU_PERIOD: u64 = 100000;
+
+/// Smallest quota accepted by the kernel, in microseconds
+const MIN_CPU_QUOTA: u64 = 1000;
+
+/// A sandbox needs a few pages to run anything at all
+const MIN_MEMORY: u64 = 1024 * 1024;
+
+const DEFAULT_MEMORY: u64 = 1024 * 1024 * 1024;
+const DEFAULT_PIDS: u32 = 100;
+const DEFAULT_CPUS: f64 = 1.0;
+
+#[derive(Debug)]
+pub enum LimitsError {
+    IOError(std::io::Error),
+    Parse,
+    Invalid(String),
+}
+
+impl std::fmt::Display for LimitsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitsError::IOError(e) => write!(f, "{}", e),
+            LimitsError::Parse => write!(f, "cannot parse the config file"),
+            LimitsError::Invalid(reason) => write!(f, "invalid limits: {}", reason),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, LimitsError>;
+
+/// Resources given to the cgroup of a sandbox, unset ones are not limited
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Limits {
+    /// Bytes, memory.max
+    pub memory: Option<u64>,
+    /// Bytes, memory.high, the sandbox is throttled above it
+    pub memory_high: Option<u64>,
+    /// Bytes, memory.swap.max
+    pub swap: Option<u64>,
+    /// Kills every process of the sandbox on OOM, memory.oom.group
+    pub oom_group: Option<bool>,
+    pub pids: Option<u32>,
+    /// Number of CPUs the sandbox may use, as 1.5
+    pub cpus: Option<f64>,
+    /// Share of the CPU time against the other sandboxes, cpu.weight
+    pub cpu_weight: Option<u32>,
+    /// CPUs and memory nodes the sandbox runs on
+    pub cpuset_cpus: Option<CpuSet>,
+    pub cpuset_mems: Option<CpuSet>,
+    /// Bandwidth of the disks, io.max
+    pub io_max: Vec<IoMax>,
+    /// Share of the disks against the other sandboxes, io.weight
+    pub io_weight: Option<u32>,
+}
+
+/// A limit of the config file, either a number or a string with a unit
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Number(f64),
+    Flag(bool),
+    Text(String),
+    List(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct LimitsSection {
+    memory: Option<Value>,
+    memory_high: Option<Value>,
+    swap: Option<Value>,
+    oom_group: Option<Value>,
+    pids: Option<Value>,
+    cpus: Option<Value>,
+    cpu_weight: Option<Value>,
+    cpuset_cpus: Option<Value>,
+    cpuset_mems: Option<Value>,
+    io_max: Option<Value>,
+    io_weight: Option<Value>,
+}
+
+/// The config file, other sections are ignored
+#[derive(Deserialize)]
+struct Config {
+    limits: Option<LimitsSection>,
+}
+
+/// Parses a number of CPUs such as 2 or 0.5
+pub fn parse_cpus(cpus: &str) -> Option<f64> {
+    cpus.trim().parse::<f64>().ok().filter(|c| c.is_finite())
+}
+
+pub fn parse_count(count: &str) -> Option<u32> {
+    count.trim().parse::<u32>().ok()
+}
+
+impl Value {
+    fn to_size(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u64),
+            Value::Text(t) => units::parse_size(t),
+            _ => None,
+        }
+    }
+
+    fn to_count(&self) -> Option<u32> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && *n <= u32::MAX as f64 && n.fract() == 0.0 => {
+                Some(*n as u32)
+            }
+            Value::Text(t) => parse_count(t),
+            _ => None,
+        }
+    }
+
+    fn to_cpus(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n).filter(|c| c.is_finite()),
+            Value::Text(t) => parse_cpus(t),
+            _ => None,
+        }
+    }
+
+    fn to_cpu_set(&self) -> Option<CpuSet> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && *n <= u32::MAX as f64 && n.fract() == 0.0 => {
+                CpuSet::parse(&n.to_string())
+            }
+            Value::Text(t) => CpuSet::parse(t),
+            _ => None,
+        }
+    }
+
+    /// Disks are resolved right away, from the host
+    fn to_io_max(&self) -> Option<Vec<IoMax>> {
+        match self {
+            Value::List(l) => l.iter().map(|spec| IoMax::parse(spec).ok()).collect(),
+            Value::Text(t) => Some(vec![IoMax::parse(t).ok()?]),
+            _ => None,
+        }
+    }
+
+    fn to_flag(&self) -> Option<bool> {
+        match self {
+            Value::Flag(f) => Some(*f),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a limit of the config file, naming it on error
+fn convert<T>(
+    name: &str,
+    value: &Option<Value>,
+    convert: impl Fn(&Value) -> Option<T>,
+) -> Result<Option<T>> {
+    match value {
+        Some(v) => match convert(v) {
+            Some(c) => Ok(Some(c)),
+            None => Err(LimitsError::Invalid(format!("{} in config file", name))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Reads the limits section of a JSON config file, as
+/// {"limits": {"memory": "4G", "swap": "0", "oom_group": true, "pids": 20, "cpus": 1.5}}
+pub fn load_config(path: &Path) -> Result<Limits> {
+    let data = fs::read(path).map_err(LimitsError::IOError)?;
+
+    let config = match serde_json::from_slice::<Config>(&data) {
+        Ok(c) => c,
+        Err(_) => {
+            error!(target:"limits", "cannot parse {:?}", path);
+            return Err(LimitsError::Parse);
+        }
+    };
+
+    let section = match config.limits {
+        Some(s) => s,
+        None => return Ok(Limits::default()),
+    };
+
+    Ok(Limits {
+        memory: convert("memory", &section.memory, Value::to_size)?,
+        memory_high: convert("memory_high", &section.memory_high, Value::to_size)?,
+        swap: convert("swap", &section.swap, Value::to_size)?,
+        oom_group: convert("oom_group", &section.oom_group, Value::to_flag)?,
+        pids: convert("pids", &section.pids, Value::to_count)?,
+        cpus: convert("cpus", &section.cpus, Value::to_cpus)?,
+        cpu_weight: convert("cpu_weight", &section.cpu_weight, Value::to_count)?,
+        cpuset_cpus: convert("cpuset_cpus", &section.cpuset_cpus, Value::to_cpu_set)?,
+        cpuset_mems: convert("cpuset_mems", &section.cpuset_mems, Value::to_cpu_set)?,
+        io_max: convert("io_max", &section.io_max, Value::to_io_max)?.unwrap_or_default(),
+        io_weight: convert("io_weight", &section.io_weight, Value::to_count)?,
+    })
+}
+
+impl Limits {
+    /// Limits applied when neither the command line nor a config file set them
+    pub fn defaults() -> Limits {
+        Limits {
+            memory: Some(DEFAULT_MEMORY),
+            memory_high: None,
+            swap: None,
+            oom_group: None,
+            pids: Some(DEFAULT_PIDS),
+            cpus: Some(DEFAULT_CPUS),
+            cpu_weight: None,
+            cpuset_cpus: None,
+            cpuset_mems: None,
+            io_max: Vec::new(),
+            io_weight: None,
+        }
+    }
+
+    /// Every limit set in other replaces ours
+    pub fn merge(&mut self, other: &Limits) {
+        self.memory = other.memory.or(self.memory);
+        self.memory_high = other.memory_high.or(self.memory_high);
+        self.swap = other.swap.or(self.swap);
+        self.oom_group = other.oom_group.or(self.oom_group);
+        self.pids = other.pids.or(self.pids);
+        self.cpus = other.cpus.or(self.cpus);
+        self.cpu_weight = other.cpu_weight.or(self.cpu_weight);
+        self.cpuset_cpus = other.cpuset_cpus.clone().or(self.cpuset_cpus.take());
+        self.cpuset_mems = other.cpuset_mems.clone().or(self.cpuset_mems.take());
+        self.io_weight = other.io_weight.or(self.io_weight);
+
+        if !other.io_max.is_empty() {
+            self.io_max = other.io_max.clone();
+        }
+    }
+
+    /// Quota of cpu.max for a period of CPU_PERIOD
+    fn cpu_quota(cpus: f64) -> u64 {
+        (cpus * CPU_PERIOD as f64).round() as u64
+    }
+
+    /// Checks the limits before anything is created
+    pub fn validate(&self) -> Result<()> {
+        if let Some(memory) = self.memory {
+            if memory < MIN_MEMORY {
+                return Err(LimitsError::Invalid(format!(
+                    "memory must be at least {} bytes",
+                    MIN_MEMORY
+                )));
+            }
+        }
+
+        if let (Some(high), Some(max)) = (self.memory_high, self.memory) {
+            if high > max {
+                return Err(LimitsError::Invalid(String::from(
+                    "memory high cannot be above memory",
+                )));
+            }
+        }
+
+        if self.pids == Some(0) {
+            return Err(LimitsError::Invalid(String::from(
+                "pids must be at least 1",
+            )));
+        }
+
+        if let Some(weight) = self.cpu_weight {
+            if weight == 0 || weight > MAX_CPU_WEIGHT {
+                return Err(LimitsError::Invalid(format!(
+                    "cpu weight must be between 1 and {}",
+                    MAX_CPU_WEIGHT
+                )));
+            }
+        }
+
+        if let Some(weight) = self.io_weight {
+            if weight == 0 || weight > MAX_IO_WEIGHT {
+                return Err(LimitsError::Invalid(format!(
+                    "io weight must be between 1 and {}",
+                    MAX_IO_WEIGHT
+                )));
+            }
+        }
+
+        // The kernel keeps one line per disk, the last one would win silently
+        for (i, io_max) in self.io_max.iter().enumerate() {
+            if self.io_max[..i].iter().any(|o| o.device == io_max.device) {
+                return Err(LimitsError::Invalid(format!(
+                    "io limits given twice for the disk {}",
+                    io_max.device
+                )));
+            }
+        }
+
+        if let Some(cpus) = self.cpus {
+            // Pinned to some CPUs, the sandbox cannot use more of them
+            let available = match &self.cpuset_cpus {
+                Some(set) => set.count(),
+                None => std::thread::available_parallelism()
+                    .map(|n| n.get() as u64)
+                    .unwrap_or(1),
+            };
+
+            if cpus <= 0.0 || Limits::cpu_quota(cpus) < MIN_CPU_QUOTA {
+                return Err(LimitsError::Invalid(format!(
+                    "cpus must be at least {}",
+                    MIN_CPU_QUOTA as f64 / CPU_PERIOD as f64
+                )));
+            }
+
+            if cpus > available as f64 {
+                return Err(LimitsError::Invalid(format!(
+                    "cpus cannot be more than the {} available",
+                    available
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the limits on a cgroup about to be created
+    pub fn apply(&self, builder: &mut CgroupV2Builder) {
+        if let Some(memory) = self.memory {
+            builder.set_mem_max(memory);
+        }
+
+        if let Some(high) = self.memory_high {
+            builder.set_mem_high(high);
+        }
+
+        if let Some(swap) = self.swap {
+            builder.set_swap_max(swap);
+        }
+
+        if let Some(oom_group) = self.oom_group {
+            builder.set_oom_group(oom_group);
+        }
+
+        if let Some(pids) = self.pids {
+            builder.set_pids_max(pids);
+        }
+
+        if let Some(cpus) = self.cpus {
+            builder.set_cpu_max(Limits::cpu_quota(cpus), CPU_PERIOD);
+        }
+
+        if let Some(weight) = self.cpu_weight {
+            builder.set_cpu_weight(weight);
+        }
+
+        if let Some(cpus) = &self.cpuset_cpus {
+            builder.set_cpuset_cpus(cpus.clone());
+        }
+
+        if let Some(mems) = &self.cpuset_mems {
+            builder.set_cpuset_mems(mems.clone());
+        }
+
+        for io_max in &self.io_max {
+            builder.add_io_max(io_max.clone());
+        }
+
+        if let Some(weight) = self.io_weight {
+            builder.set_io_weight(weight);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn load(json: &str) -> Result<Limits> {
+        let dir = TempDir::new("moulinette").unwrap();
+        let path = dir.path().join("config.json");
+
+        fs::write(&path, json).unwrap();
+
+        load_config(&path)
+    }
+
+    #[test]
+    fn config_file() {
+        let limits = load(
+            r#"{"limits": {"memory": "4G", "memory_high": 1048576, "swap": 0,
+                "oom_group": true, "pids": 20, "cpus": 1.5, "cpu_weight": "50",
+                "cpuset_cpus": "0-3", "cpuset_mems": 0}, "other": {}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(limits.memory, Some(4 << 30));
+        assert_eq!(limits.memory_high, Some(1 << 20));
+        assert_eq!(limits.swap, Some(0));
+        assert_eq!(limits.oom_group, Some(true));
+        assert_eq!(limits.pids, Some(20));
+        assert_eq!(limits.cpus, Some(1.5));
+        assert_eq!(limits.cpu_weight, Some(50));
+        assert_eq!(limits.cpuset_cpus, CpuSet::parse("0-3"));
+        assert_eq!(limits.cpuset_mems, CpuSet::parse("0"));
+        assert!(limits.io_max.is_empty());
+    }
+
+    #[test]
+    fn config_without_limits() {
+        assert_eq!(load(r#"{"other": {}}"#).unwrap(), Limits::default());
+    }
+
+    #[test]
+    fn invalid_config_values() {
+        for json in [
+            r#"{"limits": {"memory": "4X"}}"#,
+            r#"{"limits": {"memory": -1}}"#,
+            r#"{"limits": {"pids": 1.5}}"#,
+            r#"{"limits": {"oom_group": "yes"}}"#,
+            r#"{"limits": {"cpus": "many"}}"#,
+            r#"{"limits": {"cpuset_cpus": "3-1"}}"#,
+        ] {
+            assert!(
+                matches!(load(json), Err(LimitsError::Invalid(_))),
+                "{}",
+                json
+            );
+        }
+
+        assert!(matches!(load("{"), Err(LimitsError::Parse)));
+    }
+
+    #[test]
+    fn flags_override_config() {
+        let config = Limits {
+            memory: Some(2 << 30),
+            pids: Some(50),
+            cpuset_cpus: CpuSet::parse("0-1"),
+            ..Limits::default()
+        };
+        let flags = Limits {
+            memory: Some(512 << 20),
+            swap: Some(0),
+            ..Limits::default()
+        };
+
+        let mut limits = Limits::defaults();
+        limits.merge(&config);
+        limits.merge(&flags);
+
+        assert_eq!(limits.memory, Some(512 << 20));
+        assert_eq!(limits.swap, Some(0));
+        assert_eq!(limits.pids, Some(50));
+        assert_eq!(limits.cpus, Some(DEFAULT_CPUS));
+        assert_eq!(limits.cpuset_cpus, CpuSet::parse("0-1"));
+    }
+
+    #[test]
+    fn valid_limits() {
+        let limits = Limits {
+            memory_high: Some(256 << 20),
+            cpus: Some(2.0),
+            cpuset_cpus: CpuSet::parse("0-3"),
+            cpu_weight: Some(100),
+            io_weight: Some(MAX_IO_WEIGHT),
+            ..Limits::defaults()
+        };
+
+        assert!(limits.validate().is_ok());
+    }
+
+    #[test]
+    fn rejected_limits() {
+        let invalid = [
+            Limits {
+                memory: Some(MIN_MEMORY - 1),
+                ..Limits::default()
+            },
+            Limits {
+                memory: Some(256 << 20),
+                memory_high: Some(512 << 20),
+                ..Limits::default()
+            },
+            Limits {
+                pids: Some(0),
+                ..Limits::default()
+            },
+            Limits {
+                cpu_weight: Some(MAX_CPU_WEIGHT + 1),
+                ..Limits::default()
+            },
+            Limits {
+                io_weight: Some(0),
+                ..Limits::default()
+            },
+            Limits {
+                cpus: Some(0.001),
+                ..Limits::default()
+            },
+            Limits {
+                cpus: Some(3.0),
+                cpuset_cpus: CpuSet::parse("0-1"),
+                ..Limits::default()
+            },
+        ];
+
+        for limits in invalid {
+            assert!(
+                matches!(limits.validate(), Err(LimitsError::Invalid(_))),
+                "{:?}",
+                limits
+            );
+        }
+    }
+}
@@ -0,0 +1,456 @@
+use log::{error, info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, EntryType};
+
+use crate::docker_image::{self, DockerError, ImageConfig, PullOptions};
+use crate::layer::{self, ExtractReport, LayerError};
+use crate::platform::Platform;
+
+/// Links of a docker save archive may point to other links
+const MAX_LINK_DEPTH: usize = 8;
+
+#[derive(Debug)]
+pub enum LocalImageError {
+    IOError(std::io::Error),
+    Parse,
+    NotFound(String),
+    /// A sha256 digest whose hex part is malformed
+    InvalidDigest(String),
+    ArchitectureNotFound,
+    Docker(DockerError),
+    Unpack(LayerError),
+}
+
+impl std::fmt::Display for LocalImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LocalImageError::IOError(e) => write!(f, "{}", e),
+            LocalImageError::Parse => write!(f, "cannot parse the image"),
+            LocalImageError::NotFound(name) => write!(f, "{} not found in the image", name),
+            LocalImageError::InvalidDigest(digest) => write!(f, "invalid digest {}", digest),
+            LocalImageError::ArchitectureNotFound => write!(f, "no image for this platform"),
+            LocalImageError::Docker(e) => write!(f, "{}", e),
+            LocalImageError::Unpack(e) => write!(f, "cannot unpack a layer: {}", e),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, LocalImageError>;
+
+#[derive(Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType")]
+    media_type: Option<String>,
+    digest: String,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct ImageManifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+/// An entry of the manifest.json of docker save
+#[derive(Deserialize)]
+struct SavedImage {
+    #[serde(rename = "Config")]
+    config: String,
+    #[serde(rename = "RepoTags")]
+    repo_tags: Option<Vec<String>>,
+    #[serde(rename = "Layers")]
+    layers: Vec<String>,
+}
+
+/// Where the files of a local image are read from
+enum Source {
+    Directory(PathBuf),
+    /// A tar archive and the (offset, size) of each of its files
+    Archive(PathBuf, HashMap<PathBuf, (u64, u64)>),
+}
+
+/// Returns a path relative to the image root, None if it leaves it
+fn clean(path: &Path) -> Option<PathBuf> {
+    let mut cleaned = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => cleaned.push(c),
+            Component::RootDir | Component::CurDir => continue,
+            Component::ParentDir => {
+                if !cleaned.pop() {
+                    return None;
+                }
+            }
+            Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(cleaned)
+}
+
+/// Records where every file of a tar archive is, links included
+fn index_archive(path: &Path) -> Result<HashMap<PathBuf, (u64, u64)>> {
+    let file = File::open(path).map_err(LocalImageError::IOError)?;
+
+    let mut archive = Archive::new(file);
+    let mut files: HashMap<PathBuf, (u64, u64)> = HashMap::new();
+    let mut links: Vec<(PathBuf, PathBuf)> = Vec::new();
+
+    for entry in archive.entries().map_err(LocalImageError::IOError)? {
+        let entry = entry.map_err(LocalImageError::IOError)?;
+
+        let name = match clean(&entry.path().map_err(LocalImageError::IOError)?) {
+            Some(n) => n,
+            None => continue,
+        };
+
+        let link_name = entry.link_name().map_err(LocalImageError::IOError)?;
+
+        match (entry.header().entry_type(), link_name) {
+            (EntryType::Regular | EntryType::Continuous, _) => {
+                files.insert(name, (entry.raw_file_position(), entry.size()));
+            }
+            // docker save shares identical layers between images with symlinks
+            (EntryType::Symlink, Some(target)) => {
+                let parent = name.parent().unwrap_or_else(|| Path::new(""));
+                if let Some(target) = clean(&parent.join(target)) {
+                    links.push((name, target));
+                }
+            }
+            (EntryType::Link, Some(target)) => {
+                if let Some(target) = clean(&target) {
+                    links.push((name, target));
+                }
+            }
+            _ => continue,
+        }
+    }
+
+    for _ in 0..MAX_LINK_DEPTH {
+        for (name, target) in &links {
+            if let Some(position) = files.get(target).copied() {
+                files.entry(name.clone()).or_insert(position);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+impl Source {
+    fn open(path: &Path) -> Result<Source> {
+        if path.is_dir() {
+            return Ok(Source::Directory(PathBuf::from(path)));
+        }
+
+        info!(target:"local_image", "indexing {:?}", path);
+
+        Ok(Source::Archive(PathBuf::from(path), index_archive(path)?))
+    }
+
+    fn has(&self, name: &str) -> bool {
+        match (self, clean(Path::new(name))) {
+            (Source::Directory(root), Some(n)) => root.join(n).is_file(),
+            (Source::Archive(_, files), Some(n)) => files.contains_key(&n),
+            (_, None) => false,
+        }
+    }
+
+    fn open_file(&self, name: &str) -> Result<Box<dyn Read>> {
+        let not_found = || LocalImageError::NotFound(String::from(name));
+
+        let cleaned = clean(Path::new(name)).ok_or_else(not_found)?;
+
+        match self {
+            Source::Directory(root) => match File::open(root.join(cleaned)) {
+                Ok(f) => Ok(Box::new(f)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Err(not_found()),
+                Err(e) => Err(LocalImageError::IOError(e)),
+            },
+            Source::Archive(path, files) => {
+                let (offset, size) = files.get(&cleaned).copied().ok_or_else(not_found)?;
+
+                let mut file = File::open(path).map_err(LocalImageError::IOError)?;
+
+                if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+                    return Err(LocalImageError::IOError(e));
+                }
+
+                Ok(Box::new(file.take(size)))
+            }
+        }
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = Vec::new();
+
+        if let Err(e) = self.open_file(name)?.read_to_end(&mut data) {
+            return Err(LocalImageError::IOError(e));
+        }
+
+        Ok(data)
+    }
+}
+
+/// Returns the digest a file is named after, as blobs/sha256/<hex> or <hex>.json
+fn digest_of(name: &str) -> Option<String> {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    let hex = file_name.strip_suffix(".json").unwrap_or(file_name);
+
+    if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(format!("sha256:{}", hex))
+    } else {
+        None
+    }
+}
+
+fn blob_name(digest: &str) -> Result<String> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if digest_of(hex).is_some() => Ok(format!("blobs/sha256/{}", hex)),
+        None => Err(LocalImageError::Docker(DockerError::UnsupportedDigest(
+            String::from(digest),
+        ))),
+        Some(_) => Err(LocalImageError::InvalidDigest(String::from(digest))),
+    }
+}
+
+fn read_blob(source: &Source, digest: &str) -> Result<Vec<u8>> {
+    let data = source.read(&blob_name(digest)?)?;
+
+    docker_image::verify_digest(&data, digest).map_err(LocalImageError::Docker)?;
+
+    Ok(data)
+}
+
+/// Returns the compression of a layer whose media type is unknown
+fn sniff_media_type(head: &[u8]) -> &'static str {
+    match head {
+        [0x1f, 0x8b, ..] => docker_image::LAYER_GZIP,
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => docker_image::LAYER_ZSTD,
+        _ => docker_image::LAYER_TAR,
+    }
+}
+
+/// Checks a file of the image against its digest without holding it in memory
+fn verify_file(source: &Source, name: &str, digest: &str) -> Result<()> {
+    let mut hasher = Sha256::new();
+
+    if let Err(e) = io::copy(&mut source.open_file(name)?, &mut hasher) {
+        return Err(LocalImageError::IOError(e));
+    }
+
+    docker_image::verify_hash(hasher.finalize().as_slice(), digest).map_err(LocalImageError::Docker)
+}
+
+/// Applies a layer of the image. A layer with a digest is verified before
+/// anything is extracted, so the file is read twice.
+fn apply_layer(
+    source: &Source,
+    name: &str,
+    media_type: Option<&str>,
+    digest: Option<&str>,
+    output: &Path,
+    options: &PullOptions,
+    report: &mut ExtractReport,
+) -> Result<()> {
+    info!(target:"local_image_layer", "{}", name);
+
+    if let Some(d) = digest {
+        verify_file(source, name, d)?;
+    }
+
+    let mut buffered = BufReader::new(source.open_file(name)?);
+
+    let media_type = match media_type {
+        Some(m) => String::from(m),
+        None => match buffered.fill_buf() {
+            Ok(head) => String::from(sniff_media_type(head)),
+            Err(e) => return Err(LocalImageError::IOError(e)),
+        },
+    };
+
+    let tar = docker_image::decompress(&media_type, buffered).map_err(LocalImageError::Docker)?;
+
+    layer::apply(tar, output, &options.extract, report).map_err(LocalImageError::Unpack)
+}
+
+/// Picks the entry of an index matching the platform
+fn select_descriptor(mut manifests: Vec<Descriptor>, platform: &Platform) -> Result<Descriptor> {
+    // The index of a layout holding a single image usually has no platform
+    if manifests.iter().all(|m| m.platform.is_none()) {
+        if manifests.len() > 1 {
+            warn!(target:"local_image", "{} images without platform, using the first", manifests.len());
+        }
+
+        return match manifests.into_iter().next() {
+            Some(m) => Ok(m),
+            None => Err(LocalImageError::Parse),
+        };
+    }
+
+    manifests.retain(|m| m.platform.is_some());
+
+    match platform.select(manifests.iter().filter_map(|m| m.platform.as_ref())) {
+        Some(i) => Ok(manifests.swap_remove(i)),
+        None => {
+            error!(target:"local_image", "no image for {}", platform);
+            Err(LocalImageError::ArchitectureNotFound)
+        }
+    }
+}
+
+/// Unpacks the image of an OCI image layout
+fn load_oci_layout(source: &Source, output: &Path, options: &PullOptions) -> Result<ImageConfig> {
+    let platform = options.platform.clone().unwrap_or_else(Platform::host);
+
+    let mut data = source.read("index.json")?;
+
+    // Indexes may point to other indexes, such as the one of a multi-platform image
+    let manifest = loop {
+        match serde_json::from_slice::<Index>(&data) {
+            Ok(index) => {
+                let descriptor = select_descriptor(index.manifests, &platform)?;
+                info!(target:"local_image_manifest", "{}", descriptor.digest);
+                data = read_blob(source, &descriptor.digest)?;
+            }
+            Err(_) => match serde_json::from_slice::<ImageManifest>(&data) {
+                Ok(m) => break m,
+                Err(_) => return Err(LocalImageError::Parse),
+            },
+        }
+    };
+
+    let mut report = ExtractReport::default();
+
+    for descriptor in &manifest.layers {
+        apply_layer(
+            source,
+            &blob_name(&descriptor.digest)?,
+            descriptor.media_type.as_deref(),
+            Some(&descriptor.digest),
+            output,
+            options,
+            &mut report,
+        )?;
+    }
+
+    report.log();
+
+    let config = read_blob(source, &manifest.config.digest)?;
+
+    docker_image::parse_config(&config).map_err(LocalImageError::Docker)
+}
+
+/// Unpacks the image of a docker save archive
+fn load_docker_save(source: &Source, output: &Path, options: &PullOptions) -> Result<ImageConfig> {
+    let images = match serde_json::from_slice::<Vec<SavedImage>>(&source.read("manifest.json")?) {
+        Ok(i) => i,
+        Err(_) => return Err(LocalImageError::Parse),
+    };
+
+    if images.len() > 1 {
+        warn!(target:"local_image", "{} images in the archive, using the first", images.len());
+    }
+
+    let image = match images.into_iter().next() {
+        Some(i) => i,
+        None => return Err(LocalImageError::Parse),
+    };
+
+    info!(target:"local_image", "tags {:?}", image.repo_tags.unwrap_or_default());
+
+    let mut report = ExtractReport::default();
+
+    // Layers are not always named after their digest, nor is their compression known
+    for name in &image.layers {
+        apply_layer(
+            source,
+            name,
+            None,
+            digest_of(name).as_deref(),
+            output,
+            options,
+            &mut report,
+        )?;
+    }
+
+    report.log();
+
+    let config = source.read(&image.config)?;
+
+    if let Some(digest) = digest_of(&image.config) {
+        docker_image::verify_digest(&config, &digest).map_err(LocalImageError::Docker)?;
+    }
+
+    docker_image::parse_config(&config).map_err(LocalImageError::Docker)
+}
+
+/// Extracts a local image into output and returns its runtime settings
+pub fn load(path: &Path, output: &Path, options: &PullOptions) -> Result<ImageConfig> {
+    let source = Source::open(path)?;
+
+    // Recent docker save archives are also OCI layouts
+    let config = if source.has("oci-layout") && source.has("index.json") {
+        info!(target:"local_image", "{:?} is an OCI layout", path);
+        load_oci_layout(&source, output, options)?
+    } else if source.has("manifest.json") {
+        info!(target:"local_image", "{:?} is a docker save archive", path);
+        load_docker_save(&source, output, options)?
+    } else {
+        error!(target:"local_image", "{:?} is not an image", path);
+        return Err(LocalImageError::NotFound(String::from("index.json")));
+    };
+
+    info!(target:"local_image", "{:?} extracted", path);
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn blob_names() {
+        assert_eq!(
+            blob_name(&format!("sha256:{}", HEX)).unwrap(),
+            format!("blobs/sha256/{}", HEX)
+        );
+        assert!(matches!(
+            blob_name(&format!("sha512:{}", HEX)),
+            Err(LocalImageError::Docker(DockerError::UnsupportedDigest(_)))
+        ));
+        assert!(matches!(
+            blob_name("sha256:../../etc/passwd"),
+            Err(LocalImageError::InvalidDigest(_))
+        ));
+    }
+
+    #[test]
+    fn sniffed_media_types() {
+        assert_eq!(
+            sniff_media_type(&[0x1f, 0x8b, 0x08]),
+            docker_image::LAYER_GZIP
+        );
+        assert_eq!(
+            sniff_media_type(&[0x28, 0xb5, 0x2f, 0xfd]),
+            docker_image::LAYER_ZSTD
+        );
+        assert_eq!(sniff_media_type(b"etc/"), docker_image::LAYER_TAR);
+    }
+}
@@ -1,87 +1,514 @@
 use anyhow::Result;
 use caps::errors::CapsError;
 use caps::CapSet;
+use cgroup::{CgroupV2, CpuSet, IoMax};
+use docker_image::{ImageConfig, PullOptions};
+use inject::CopyEntry;
+use layer::ExtractOptions;
+use limits::Limits;
 use log::info;
 use nix::sched::unshare;
 use nix::sched::CloneFlags;
 use nix::unistd::sethostname;
+use output::OutputCollector;
+use platform::Platform;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use report::{Report, ReportFormat, Snapshot};
+use runtime::{Overrides, RuntimeError};
+use safe_env::{BindMount, Environment, EnvironmentOptions, RootfsSource, TmpfsMount};
+use sandbox::{SandboxState, StateDir};
 use seccomp::Context;
 use seccomp_sys::SCMP_ACT_ALLOW;
 use seccomp_sys::SCMP_ACT_ERRNO;
 use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::process::Command;
 use std::process::Stdio;
 use syscall_numbers::x86_64::{SYS_nfsservctl, SYS_personality, SYS_pivot_root};
+use verdict::Verdict;
 
 mod cgroup;
+mod credentials;
 mod docker_image;
+mod image_cache;
+mod inject;
+mod layer;
+mod limits;
+mod local_image;
+mod output;
+mod platform;
+mod reference;
+mod report;
+mod runtime;
 mod safe_env;
+mod sandbox;
 mod seccomp;
+mod units;
+mod verdict;
+
+/// Default total size of the files copied out of the sandbox
+const DEFAULT_OUTPUT_MAX_SIZE: u64 = 64 * 1024 * 1024;
 
 #[derive(Debug)]
 struct Arguments {
-    binary_name: String,
-    binary_args: Vec<String>,
-    workdir: Option<String>,
-    rootfs: Option<String>,
+    overrides: Overrides,
+    environment: EnvironmentOptions,
+    pull: PullArguments,
+    output: OutputArguments,
+    limits: Limits,
+    report: Option<ReportFormat>,
+}
+
+/// Files copied out of the sandbox after the run
+#[derive(Debug)]
+struct OutputArguments {
+    patterns: Vec<String>,
+    dir: Option<String>,
+    max_size: u64,
+}
+
+/// Registry settings given on the command line
+#[derive(Debug, Default)]
+struct PullArguments {
+    username: Option<String>,
+    password_file: Option<String>,
+    platform: Option<String>,
+    strip_setid: bool,
+    offline: bool,
+}
+
+#[derive(Debug)]
+enum ImageCommand {
+    Pull(String, PullArguments),
+    List,
+    Prune(bool),
+}
+
+#[derive(Debug)]
+enum Action {
+    Run(Box<Arguments>),
+    Image(ImageCommand),
+    /// Builds a named sandbox kept alive until deleted
+    Create(String, Box<Arguments>),
+    Exec(String, Overrides, Option<ReportFormat>),
+    List,
+    Delete(String),
+    /// Process holding the namespaces of a named sandbox
+    Hold,
 }
 
 fn print_help() {
     println!("MyMoulette, the students' nightmare, now highly secured");
-    println!("Usage: ./mymoulette [-v student_workdir] <-I docker-img|rootfs-path> moulette_prog [moulette_arg [...]]");
-    println!("\trootfs-path is the path to the directory containing the new rootfs (exclusive with -Ioption)");
-    println!("\tdocker-img is an image available on hub.docker.com (exclusive with rootfs-path)");
+    println!("Usage: ./mymoulette [-v src[:dst[:ro|rw]]] [-u username -p password_file] [--platform platform] [-e KEY[=VALUE]] [-w dir] [--user user[:group]] [--entrypoint prog] [--strip-setid] [--offline] [--read-only] [--tmpfs path[:size]] [--copy src:dst[:mode[:uid[:gid]]]] [--copy-manifest file] [--memory size] [--memory-high size] [--swap size] [--oom-group] [--pids n] [--cpus n] [--cpu-weight n] [--cpuset-cpus list] [--cpuset-mems list] [--io-max path:limits] [--io-weight n] [--config file] [--report human|json] [-o pattern --output-dir dir [--output-max-size size]] <-I docker-img|--rootfs rootfs-dir [--diff-dir dir]|--local-image image-path> [moulette_prog [moulette_arg [...]]]");
+    println!("\tdocker-img is an image reference as [registry[:port]/]repository[:tag][@digest], Docker Hub by default");
+    println!("\trootfs-dir is a directory of the host mounted read-only below an overlay, it is never modified");
+    println!("\tthe changes made to rootfs-dir are discarded, or kept in the empty directory given by --diff-dir");
+    println!("\timage-path is an OCI image layout directory or a docker save tar archive");
+    println!("\tonly one of -I, --rootfs and --local-image may be given");
+    println!("\t--read-only remounts the rootfs read-only, with tmpfs on /tmp and /run");
+    println!("\t--tmpfs mounts a writable tmpfs of size bytes, as 64m (the default), 1g or 10%, can be repeated");
     println!("\tmoulette_prog will be the first program to be launched, must already be in the environment");
-    println!("\tstudent_workdir is the directory containing the code to grade");
+    println!("\tlike docker, moulette_prog is given to the image Entrypoint and defaults to the image Cmd");
+    println!("\t-e, -w, --user and --entrypoint override the Env, WorkingDir, User and Entrypoint of the image");
+    println!("\t--copy copies the host path src to dst in the rootfs before the program starts, it can be repeated");
+    println!("\tmode is octal and applies to dst, uid and gid own everything copied");
+    println!("\t--copy-manifest reads the copies from a JSON array of {{source, target, mode, uid, gid}}, sources being relative to it");
+    println!("\t--memory and --swap limit the memory and the swap of the sandbox, as 512m (1g of memory by default) or 2g");
+    println!("\t--memory-high throttles the sandbox above size, --oom-group makes an OOM kill every process of the sandbox");
+    println!("\t--pids limits the number of processes (100 by default), --cpus the CPU time as a number of CPUs, as 1.5 (1 by default)");
+    println!("\t--cpu-weight sets the share of CPU time against other sandboxes, from 1 to 10000 (100 by default)");
+    println!("\t--cpuset-cpus and --cpuset-mems restrict the CPUs and memory nodes used, as 0-3,6");
+    println!("\t--io-max limits the disk holding path with limits as rbps=10m,wbps=10m,riops=100,wiops=100, it can be repeated");
+    println!("\t--io-weight sets the share of the disks against other sandboxes, from 1 to 10000 (100 by default)");
+    println!("\t--config reads the limits of a JSON file {{\"limits\": {{memory, memory_high, swap, oom_group, pids, cpus, cpu_weight, cpuset_cpus, cpuset_mems, io_max, io_weight}}}}, the options above take precedence");
+    println!("\t--report prints the verdict and the CPU time, memory, processes and I/O used on stderr once the program exited");
+    println!("\t-o copies the sandbox files matching pattern, as /results/*.xml, to dir once the program exited, it can be repeated");
+    println!(
+        "\tat most size bytes are copied, as 64m (the default) or 1g, symlinks are not followed"
+    );
+    println!("\t-v binds the host path src on dst, read-only with ro, it can be repeated");
+    println!("\tdst defaults to /home/student, for the directory containing the code to grade");
+    println!("\tusername and password_file are the registry credentials, read from ~/.docker/config.json by default");
+    println!("\tplatform is os/arch[/variant], as linux/arm/v7, the one of the host by default");
+    println!("\ta cached docker-img is checked against the registry and updated if its tag moved, --offline only uses the cache");
+    println!("\t--strip-setid removes the setuid and setgid bits of the image files, device nodes are never extracted");
+    println!("Usage: ./mymoulette create name [options] <-I docker-img|--rootfs rootfs-dir|--local-image image-path>");
+    println!("\tbuilds the sandbox name with the options above and keeps it alive, without running a program");
+    println!("Usage: ./mymoulette exec name [--report human|json] [-e KEY[=VALUE]] [-w dir] [--user user[:group]] [--entrypoint prog] [moulette_prog [moulette_arg [...]]]");
+    println!("\truns a program in the sandbox name, joining its namespaces and cgroup");
+    println!("Usage: ./mymoulette <list|delete name>");
+    println!("\tlist shows the named sandboxes, delete kills the processes of one and removes it");
+    println!("Usage: ./mymoulette image <pull [-u username -p password_file] [--platform platform] docker-img|list|prune [-a]>");
+    println!("\tpull downloads docker-img into the local cache, even if it is already there");
+    println!("\tlist shows the cached images");
+    println!("\tprune removes the cached blobs no image uses anymore, or everything with -a");
+    println!("\tthe cache is stored in /var/cache/moulinette unless MOULINETTE_CACHE_DIR is set");
+}
+
+fn exit_with_help() -> ! {
+    print_help();
+    process::exit(1);
+}
+
+/// Returns the value following an option, exits if there is none
+fn expect_value<'a>(items: &mut impl Iterator<Item = &'a String>) -> String {
+    match items.next() {
+        Some(v) => v.clone(),
+        None => exit_with_help(),
+    }
+}
+
+/// Parses the registry options, returns false if item is not one
+fn parse_pull_argument<'a>(
+    item: &str,
+    items: &mut impl Iterator<Item = &'a String>,
+    pull: &mut PullArguments,
+) -> bool {
+    match item {
+        "-u" => pull.username = Some(expect_value(items)),
+        "-p" => pull.password_file = Some(expect_value(items)),
+        "--platform" => pull.platform = Some(expect_value(items)),
+        _ => return false,
+    }
+
+    true
+}
+
+/// Parses the options overriding the image config, returns false if item is not one
+fn parse_override_argument<'a>(
+    item: &str,
+    items: &mut impl Iterator<Item = &'a String>,
+    overrides: &mut Overrides,
+) -> bool {
+    match item {
+        "-e" => overrides.env.push(expect_value(items)),
+        "-w" => overrides.working_dir = Some(expect_value(items)),
+        "--entrypoint" => overrides.entrypoint = Some(expect_value(items)),
+        "--user" => overrides.user = Some(expect_value(items)),
+        _ => return false,
+    }
+
+    true
+}
+
+/// Returns the image subcommand parsed from the command line
+fn parse_image_command(args: &[String]) -> ImageCommand {
+    let mut items = args.iter();
+
+    let command = match items.next().map(String::as_str) {
+        Some("pull") => {
+            let mut pull = PullArguments::default();
+            let mut image: Option<String> = None;
+
+            while let Some(item) = items.next() {
+                if parse_pull_argument(item, &mut items, &mut pull) {
+                    continue;
+                }
+
+                if image.is_some() {
+                    exit_with_help();
+                }
+
+                image = Some(item.clone());
+            }
+
+            image.map(|i| ImageCommand::Pull(i, pull))
+        }
+        Some("list") => Some(ImageCommand::List),
+        Some("prune") => match items.next().map(String::as_str) {
+            None => Some(ImageCommand::Prune(false)),
+            Some("-a") => Some(ImageCommand::Prune(true)),
+            Some(_) => None,
+        },
+        _ => None,
+    };
+
+    match (command, items.next()) {
+        (Some(c), None) => c,
+        _ => exit_with_help(),
+    }
 }
 
-/// Returns the parsed arguments from the command line
-fn parse_arguments() -> Arguments {
+/// Only one rootfs source may be given
+fn set_rootfs(rootfs: &mut Option<RootfsSource>, source: RootfsSource) {
+    if rootfs.is_some() {
+        exit_with_help();
+    }
+
+    *rootfs = Some(source);
+}
+
+/// Returns the sandbox name given to a subcommand
+fn expect_name(name: Option<&String>) -> String {
+    match name {
+        Some(n) if sandbox::validate_name(n) => n.clone(),
+        _ => exit_with_help(),
+    }
+}
+
+/// Returns the command of exec, only the image config can be overridden
+fn parse_exec_arguments(args: &[String]) -> (Overrides, Option<ReportFormat>) {
+    let mut overrides = Overrides::default();
+    let mut report: Option<ReportFormat> = None;
+
+    let mut items = args.iter();
+
+    while let Some(item) = items.next() {
+        if parse_override_argument(item, &mut items, &mut overrides) {
+            continue;
+        }
+
+        if item == "--report" {
+            report = Some(expect_report_format(&mut items));
+            continue;
+        }
+
+        overrides.command.push(item.clone());
+        overrides.command.extend(items.cloned());
+        break;
+    }
+
+    (overrides, report)
+}
+
+fn expect_report_format<'a>(items: &mut impl Iterator<Item = &'a String>) -> ReportFormat {
+    match ReportFormat::parse(&expect_value(items)) {
+        Some(f) => f,
+        None => exit_with_help(),
+    }
+}
+
+/// Returns the parsed action from the command line
+fn parse_arguments() -> Action {
     let args: Vec<String> = env::args().collect();
 
-    let mut binary_name: String = String::new();
-    let mut binary_args: Vec<String> = Vec::new();
-    let mut workdir: Option<String> = Option::None;
-    let mut rootfs: Option<String> = Option::None;
+    match args.get(1).map(String::as_str) {
+        Some("image") => Action::Image(parse_image_command(&args[2..])),
+        Some("create") => {
+            let name = expect_name(args.get(2));
+            let arguments = parse_run_arguments(&args[3..]);
+
+            // The programs are started by exec
+            if !arguments.overrides.command.is_empty() || arguments.output.dir.is_some() {
+                exit_with_help();
+            }
 
-    let mut expecting_workdir: bool = false;
-    let mut expecting_rootfs: bool = false;
-    let mut is_binary_name_set: bool = false;
+            Action::Create(name, Box::new(arguments))
+        }
+        Some("exec") => {
+            let name = expect_name(args.get(2));
+            let (overrides, report) = parse_exec_arguments(&args[3..]);
+            Action::Exec(name, overrides, report)
+        }
+        Some("list") if args.len() == 2 => Action::List,
+        Some("delete") if args.len() == 3 => Action::Delete(expect_name(args.get(2))),
+        Some(sandbox::HOLD_COMMAND) => Action::Hold,
+        _ => Action::Run(Box::new(parse_run_arguments(&args[1..]))),
+    }
+}
+
+/// Returns the settings of a sandbox and of its program
+fn parse_run_arguments(args: &[String]) -> Arguments {
+    let mut overrides = Overrides::default();
+    let mut environment = EnvironmentOptions::default();
+    let mut output = OutputArguments {
+        patterns: Vec::new(),
+        dir: None,
+        max_size: DEFAULT_OUTPUT_MAX_SIZE,
+    };
+    let mut pull = PullArguments::default();
+    let mut limits = Limits::default();
+    let mut config: Option<Limits> = None;
+    let mut report: Option<ReportFormat> = None;
+
+    let mut items = args.iter();
+
+    while let Some(item) = items.next() {
+        if parse_pull_argument(item, &mut items, &mut pull)
+            || parse_override_argument(item, &mut items, &mut overrides)
+        {
+            continue;
+        }
 
-    for item in args.iter().skip(1) {
         match item.as_str() {
-            "-v" => expecting_workdir = true,
-            "-I" => expecting_rootfs = true,
-            s => {
-                if expecting_workdir {
-                    expecting_workdir = false;
-                    workdir = Some(String::from(s));
-                } else if expecting_rootfs {
-                    expecting_rootfs = false;
-                    rootfs = Some(String::from(s));
-                } else if is_binary_name_set {
-                    binary_args.push(String::from(s));
-                } else {
-                    binary_name = String::from(s);
-                    is_binary_name_set = true;
+            "-v" => match BindMount::parse(&expect_value(&mut items)) {
+                Some(b) => environment.binds.push(b),
+                None => exit_with_help(),
+            },
+            "-I" => set_rootfs(
+                &mut environment.rootfs,
+                RootfsSource::Registry(expect_value(&mut items)),
+            ),
+            "--rootfs" => set_rootfs(
+                &mut environment.rootfs,
+                RootfsSource::Directory(PathBuf::from(expect_value(&mut items))),
+            ),
+            "--local-image" => set_rootfs(
+                &mut environment.rootfs,
+                RootfsSource::LocalImage(PathBuf::from(expect_value(&mut items))),
+            ),
+            "--diff-dir" => environment.diff_dir = Some(PathBuf::from(expect_value(&mut items))),
+            "--read-only" => environment.read_only = true,
+            "--tmpfs" => match TmpfsMount::parse(&expect_value(&mut items)) {
+                Some(t) => environment.tmpfs.push(t),
+                None => exit_with_help(),
+            },
+            "--copy" => match CopyEntry::parse(&expect_value(&mut items)) {
+                Some(c) => environment.copies.push(c),
+                None => exit_with_help(),
+            },
+            "--copy-manifest" => environment.copies.extend(
+                inject::load_manifest(Path::new(&expect_value(&mut items)))
+                    .expect("Failed to read copy manifest"),
+            ),
+            "-o" => output.patterns.push(expect_value(&mut items)),
+            "--output-dir" => output.dir = Some(expect_value(&mut items)),
+            "--output-max-size" => match units::parse_size(&expect_value(&mut items)) {
+                Some(s) => output.max_size = s,
+                None => exit_with_help(),
+            },
+            "--memory" => match units::parse_size(&expect_value(&mut items)) {
+                Some(m) => limits.memory = Some(m),
+                None => exit_with_help(),
+            },
+            "--memory-high" => match units::parse_size(&expect_value(&mut items)) {
+                Some(m) => limits.memory_high = Some(m),
+                None => exit_with_help(),
+            },
+            "--oom-group" => limits.oom_group = Some(true),
+            "--swap" => match units::parse_size(&expect_value(&mut items)) {
+                Some(m) => limits.swap = Some(m),
+                None => exit_with_help(),
+            },
+            "--pids" => match limits::parse_count(&expect_value(&mut items)) {
+                Some(p) => limits.pids = Some(p),
+                None => exit_with_help(),
+            },
+            "--cpus" => match limits::parse_cpus(&expect_value(&mut items)) {
+                Some(c) => limits.cpus = Some(c),
+                None => exit_with_help(),
+            },
+            "--cpu-weight" => match limits::parse_count(&expect_value(&mut items)) {
+                Some(w) => limits.cpu_weight = Some(w),
+                None => exit_with_help(),
+            },
+            "--cpuset-cpus" => match CpuSet::parse(&expect_value(&mut items)) {
+                Some(c) => limits.cpuset_cpus = Some(c),
+                None => exit_with_help(),
+            },
+            "--cpuset-mems" => match CpuSet::parse(&expect_value(&mut items)) {
+                Some(m) => limits.cpuset_mems = Some(m),
+                None => exit_with_help(),
+            },
+            "--io-max" => match IoMax::parse(&expect_value(&mut items)) {
+                Ok(i) => limits.io_max.push(i),
+                Err(e) => {
+                    eprintln!("Invalid io limit: {:?}", e);
+                    exit_with_help();
                 }
+            },
+            "--io-weight" => match limits::parse_count(&expect_value(&mut items)) {
+                Some(w) => limits.io_weight = Some(w),
+                None => exit_with_help(),
+            },
+            "--config" => {
+                config = Some(
+                    limits::load_config(Path::new(&expect_value(&mut items)))
+                        .expect("Failed to read config file"),
+                )
+            }
+            "--report" => report = Some(expect_report_format(&mut items)),
+            "--strip-setid" => pull.strip_setid = true,
+            "--offline" => pull.offline = true,
+            s => {
+                // Everything after the program belongs to it
+                overrides.command.push(String::from(s));
+                overrides.command.extend(items.cloned());
+                break;
             }
         }
     }
 
-    if !is_binary_name_set {
-        print_help();
-        process::exit(1);
+    // Only a host directory is mounted below an overlay
+    if environment.diff_dir.is_some()
+        && !matches!(environment.rootfs, Some(RootfsSource::Directory(_)))
+    {
+        exit_with_help();
+    }
+
+    // Patterns and directory go together
+    if output.patterns.is_empty() != output.dir.is_none() {
+        exit_with_help();
+    }
+
+    // The command line wins over the config file, which wins over the defaults
+    let mut merged = Limits::defaults();
+    if let Some(config) = &config {
+        merged.merge(config);
+    }
+    merged.merge(&limits);
+
+    // Checked now, a bad limit must not leave a cgroup behind
+    if let Err(e) = merged.validate() {
+        eprintln!("Invalid limits: {:?}", e);
+        exit_with_help();
     }
 
     Arguments {
-        binary_name,
-        binary_args,
-        workdir,
-        rootfs,
+        overrides,
+        environment,
+        pull,
+        output,
+        limits: merged,
+        report,
+    }
+}
+
+/// Returns the pull options matching the registry settings of the command line
+fn pull_options(pull: &PullArguments) -> PullOptions {
+    let credentials = match (&pull.username, &pull.password_file) {
+        (Some(username), Some(password_file)) => Some(
+            credentials::from_password_file(username, Path::new(password_file))
+                .expect("Failed to read password file"),
+        ),
+        (None, None) => None,
+        _ => exit_with_help(),
+    };
+
+    let platform = pull.platform.as_ref().map(|p| match Platform::parse(p) {
+        Ok(p) => p,
+        Err(_) => exit_with_help(),
+    });
+
+    PullOptions {
+        credentials,
+        platform,
+        extract: ExtractOptions {
+            strip_setid: pull.strip_setid,
+        },
+        offline: pull.offline,
+    }
+}
+
+fn run_image_command(command: ImageCommand) {
+    match command {
+        ImageCommand::Pull(image, pull) => {
+            let digest =
+                docker_image::pull(&image, &pull_options(&pull)).expect("Failed to pull image");
+            println!("{} {}", image, digest);
+        }
+        ImageCommand::List => {
+            let images = docker_image::list().expect("Failed to list images");
+            for (reference, digest, size) in images {
+                println!("{}\t{}\t{}", reference, digest, size);
+            }
+        }
+        ImageCommand::Prune(all) => {
+            let (removed, freed) = docker_image::prune(all).expect("Failed to prune images");
+            println!("{} blobs removed, {} bytes freed", removed, freed);
+        }
     }
 }
 
@@ -111,51 +538,42 @@ fn set_allowed_syscalls() -> Result<()> {
 
     Ok(())
 }
-fn main() {
-    env_logger::init();
-
-    info!(target:"main", "parsing arguments");
-    let args: Arguments = parse_arguments();
-
+/// Builds the sandbox, the calling process ends up inside of it
+fn create_sandbox(name: &str, args: &Arguments) -> (Environment, CgroupV2) {
     unshare(CloneFlags::CLONE_NEWNS).expect("Failed to unshare");
 
-    let hostname: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(10)
-        .map(char::from)
-        .collect();
-
-    info!(target:"main", "generated random hostname {}", hostname);
-
     // Creating the cgroup
-    let cgroup = cgroup::CgroupV2Builder::new(&hostname)
-        .add_pid(process::id())
-        .set_cpus_number(1)
-        .set_mem_max(1073741824)
-        .set_pids_max(100)
-        .create()
-        .expect("Failed to create cgroup");
+    // Only the programs join it, an OOM must never kill us
+    let mut builder = cgroup::CgroupV2Builder::new(name);
+    args.limits.apply(&mut builder);
+
+    let cgroup = builder.create().expect("Failed to create cgroup");
 
-    info!(target:"main", "process added to cgroup");
+    info!(target:"main", "cgroup created");
 
-    safe_env::create_environment(args.workdir.as_ref(), args.rootfs.as_ref())
+    let environment = safe_env::create_environment(&args.environment, &pull_options(&args.pull))
         .expect("Failed to create environment");
 
     info!(target:"main", "safe environment created");
 
+    // The cgroup namespace is created by the programs once in the cgroup
     unshare(
-        CloneFlags::CLONE_NEWCGROUP
-            | CloneFlags::CLONE_NEWIPC
+        CloneFlags::CLONE_NEWIPC
             | CloneFlags::CLONE_NEWNET
             | CloneFlags::CLONE_NEWPID
             | CloneFlags::CLONE_NEWUTS,
     )
     .expect("Failed to unshare");
 
-    info!(target:"main", "unshare NEWCGROUP NEWIPC NEWNET NEWPID NEWUTS");
+    info!(target:"main", "unshare NEWIPC NEWNET NEWPID NEWUTS");
 
-    sethostname(&hostname).expect("Failed to set hostname");
+    sethostname(name).expect("Failed to set hostname");
 
+    (environment, cgroup)
+}
+
+/// Restricts what the programs started from now on can do
+fn confine() {
     drop_capabilities().expect("Failed to drop capabilities");
 
     info!(target:"main", "capabilities dropped");
@@ -163,20 +581,165 @@ fn main() {
     set_allowed_syscalls().expect("Failed to set up syscalls");
 
     info!(target:"main", "syscall filtered");
+}
 
-    let mut proc = Command::new(args.binary_name)
-        .args(&args.binary_args)
+/// Starts the program of the sandbox in its cgroup and waits for it. The
+/// program joins the cgroup namespace ns, or creates one if None.
+fn run_program(
+    cgroup: &CgroupV2,
+    ns: Option<File>,
+    image_config: &ImageConfig,
+    overrides: &Overrides,
+    hostname: &str,
+) -> Report {
+    // A new cgroup namespace goes with a new cgroup, holding only this run
+    let fresh = ns.is_none();
+
+    let enter = cgroup.enter_hook(ns).expect("Failed to prepare cgroup");
+
+    let mut command = match runtime::build_command(image_config, overrides, hostname, enter) {
+        Ok(c) => c,
+        Err(RuntimeError::NoCommand) => exit_with_help(),
+        Err(e) => panic!("Failed to prepare process: {:?}", e),
+    };
+
+    let before = Snapshot::take(cgroup, fresh);
+
+    let mut proc = command
         .stdout(Stdio::inherit())
         .stdin(Stdio::inherit())
         .stderr(Stdio::inherit())
         .spawn()
         .expect("Failed to execute process");
 
-    let exit = proc.wait().expect("Failed to wait");
+    let status = proc.wait().expect("Failed to wait");
+
+    Report::collect(cgroup, &before, status)
+}
+
+fn exit_with_report(report: &Report, format: Option<ReportFormat>) -> ! {
+    info!(target:"exit_code", "{}", report.verdict);
+
+    match format {
+        Some(format) => report.print(format),
+        // A crash must not be mistaken for a failure of the program
+        None if !matches!(report.verdict, Verdict::Exited(_)) => {
+            eprintln!("moulinette: program {}", report.verdict)
+        }
+        None => (),
+    }
+
+    std::process::exit(report.verdict.exit_code());
+}
+
+fn run(args: Arguments) -> ! {
+    let hostname: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect();
+
+    info!(target:"main", "generated random hostname {}", hostname);
+
+    // The output directory of the host cannot be opened from the sandbox
+    let collector = args.output.dir.as_ref().map(|dir| {
+        OutputCollector::open(Path::new(dir), &args.output.patterns, args.output.max_size)
+            .expect("Failed to open output directory")
+    });
+
+    let (environment, cgroup) = create_sandbox(&hostname, &args);
+
+    confine();
+
+    let report = run_program(
+        &cgroup,
+        None,
+        &environment.image_config,
+        &args.overrides,
+        &hostname,
+    );
+
+    if let Some(collector) = &collector {
+        collector.collect().expect("Failed to collect output");
+    }
+
+    // Unlike named sandboxes, nothing is left on the host after a run
+    sandbox::destroy_cgroup(&cgroup).expect("Failed to remove cgroup");
+
+    environment
+        .remove_host_dir()
+        .expect("Failed to remove rootfs");
+
+    exit_with_report(&report, args.report);
+}
+
+fn create(name: &str, args: Arguments) {
+    // The state directory of the host cannot be opened from the sandbox
+    let state_dir = StateDir::create(name).expect("Failed to create sandbox state");
+
+    let (environment, cgroup) = create_sandbox(name, &args);
+
+    let enter = cgroup.enter_hook(None).expect("Failed to prepare cgroup");
 
-    let exit_code = exit.code().expect("Failed to retrieve exit code");
+    let pid = sandbox::spawn_holder(enter).expect("Failed to start sandbox");
 
-    info!(target:"exit_code", "{}", exit_code);
+    state_dir
+        .save(&SandboxState {
+            name: String::from(name),
+            pid,
+            hostname: String::from(name),
+            host_dir: environment.host_dir,
+            image_config: environment.image_config,
+            created: sandbox::now(),
+        })
+        .expect("Failed to save sandbox state");
 
-    std::process::exit(exit_code);
+    println!("{} {}", name, pid);
+}
+
+fn exec(name: &str, overrides: Overrides, format: Option<ReportFormat>) -> ! {
+    let state = sandbox::load(name).expect("Failed to find sandbox");
+
+    let cgroup = CgroupV2::open(&state.name).expect("Failed to open cgroup");
+
+    let ns = sandbox::enter(&state).expect("Failed to enter sandbox");
+
+    confine();
+
+    let report = run_program(
+        &cgroup,
+        Some(ns),
+        &state.image_config,
+        &overrides,
+        &state.hostname,
+    );
+
+    exit_with_report(&report, format);
+}
+
+fn list() {
+    let sandboxes = sandbox::list().expect("Failed to list sandboxes");
+
+    for (state, alive) in sandboxes {
+        let status = if alive { "running" } else { "stopped" };
+        println!(
+            "{}\t{}\t{}\t{}",
+            state.name, state.pid, status, state.created
+        );
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    info!(target:"main", "parsing arguments");
+    match parse_arguments() {
+        Action::Run(a) => run(*a),
+        Action::Image(c) => run_image_command(c),
+        Action::Create(name, a) => create(&name, *a),
+        Action::Exec(name, o, r) => exec(&name, o, r),
+        Action::List => list(),
+        Action::Delete(name) => sandbox::delete(&name).expect("Failed to delete sandbox"),
+        Action::Hold => sandbox::hold(),
+    }
 }
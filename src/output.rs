@@ -0,0 +1,189 @@
+use log::{info, warn};
+use nix::libc;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub enum OutputError {
+    IOError(std::io::Error),
+    InvalidPattern(String),
+    NotADirectory(PathBuf),
+}
+
+impl std::fmt::Display for OutputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputError::IOError(e) => write!(f, "{}", e),
+            OutputError::InvalidPattern(pattern) => write!(f, "invalid pattern {}", pattern),
+            OutputError::NotADirectory(path) => write!(f, "{:?} is not a directory", path),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, OutputError>;
+
+/// Copies files of the sandbox to a host directory once the program exited
+pub struct OutputCollector {
+    /// Opened before the switch of root, the host is not reachable afterwards
+    dir: File,
+    patterns: Vec<String>,
+    /// Total size of the copied files
+    max_size: u64,
+}
+
+/// What was copied so far
+#[derive(Debug, Default)]
+pub struct Collected {
+    pub files: usize,
+    pub bytes: u64,
+    pub skipped: usize,
+}
+
+/// Returns true if a component of path is a symlink. They could lead outside
+/// of the sandbox, through /proc for instance, so they are never followed.
+fn has_symlink(path: &Path) -> bool {
+    path.ancestors().filter(|p| p.parent().is_some()).any(|p| {
+        fs::symlink_metadata(p)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false)
+    })
+}
+
+impl OutputCollector {
+    /// Must be called before the sandbox is created
+    pub fn open(dir: &Path, patterns: &[String], max_size: u64) -> Result<OutputCollector> {
+        for pattern in patterns {
+            if !pattern.starts_with('/') || glob::Pattern::new(pattern).is_err() {
+                return Err(OutputError::InvalidPattern(pattern.clone()));
+            }
+        }
+
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(OutputError::IOError(e));
+        }
+
+        let file = File::open(dir).map_err(OutputError::IOError)?;
+
+        if !file.metadata().map_err(OutputError::IOError)?.is_dir() {
+            return Err(OutputError::NotADirectory(PathBuf::from(dir)));
+        }
+
+        info!(target:"output", "results go to {:?}", dir);
+
+        Ok(OutputCollector {
+            dir: file,
+            patterns: patterns.to_vec(),
+            max_size,
+        })
+    }
+
+    /// The host directory, as seen from the sandbox
+    fn host_dir(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.dir.as_raw_fd()))
+    }
+
+    /// Copies the files matching the patterns, keeping their path in the sandbox
+    pub fn collect(&self) -> Result<Collected> {
+        let mut collected = Collected::default();
+
+        for pattern in &self.patterns {
+            let paths = match glob::glob(pattern) {
+                Ok(p) => p,
+                Err(_) => return Err(OutputError::InvalidPattern(pattern.clone())),
+            };
+
+            let mut matched = false;
+
+            for path in paths {
+                match path {
+                    Ok(p) => self.copy(&p, &mut collected)?,
+                    Err(e) => warn!(target:"output", "{}", e),
+                }
+
+                matched = true;
+            }
+
+            if !matched {
+                warn!(target:"output", "nothing matches {}", pattern);
+            }
+        }
+
+        info!(target:"output", "{} files copied, {} bytes, {} skipped",
+            collected.files, collected.bytes, collected.skipped);
+
+        Ok(collected)
+    }
+
+    fn copy(&self, path: &Path, collected: &mut Collected) -> Result<()> {
+        if has_symlink(path) {
+            warn!(target:"output", "{:?} skipped, it goes through a symlink", path);
+            collected.skipped += 1;
+            return Ok(());
+        }
+
+        let metadata = fs::symlink_metadata(path).map_err(OutputError::IOError)?;
+
+        let dst = self.host_dir().join(path.strip_prefix("/").unwrap_or(path));
+
+        if metadata.is_dir() {
+            if let Err(e) = fs::create_dir_all(&dst) {
+                return Err(OutputError::IOError(e));
+            }
+
+            for entry in fs::read_dir(path).map_err(OutputError::IOError)? {
+                let entry = entry.map_err(OutputError::IOError)?;
+                self.copy(&entry.path(), collected)?;
+            }
+
+            return Ok(());
+        }
+
+        if !metadata.is_file() {
+            warn!(target:"output", "{:?} skipped, not a regular file", path);
+            collected.skipped += 1;
+            return Ok(());
+        }
+
+        let remaining = self.max_size - collected.bytes;
+
+        if metadata.len() > remaining {
+            warn!(target:"output", "{:?} skipped, output limit of {} bytes reached", path, self.max_size);
+            collected.skipped += 1;
+            return Ok(());
+        }
+
+        if let Some(parent) = dst.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                return Err(OutputError::IOError(e));
+            }
+        }
+
+        let src = match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+        {
+            Ok(f) => f,
+            Err(e) => return Err(OutputError::IOError(e)),
+        };
+
+        let mut dst_file = File::create(&dst).map_err(OutputError::IOError)?;
+
+        // The file may have grown since it was checked
+        let copied = match io::copy(&mut src.take(remaining), &mut dst_file) {
+            Ok(c) => c,
+            Err(e) => return Err(OutputError::IOError(e)),
+        };
+
+        info!(target:"output", "{:?} copied, {} bytes", path, copied);
+
+        collected.files += 1;
+        collected.bytes += copied;
+
+        Ok(())
+    }
+}
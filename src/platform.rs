@@ -0,0 +1,219 @@
+use serde::Deserialize;
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub enum PlatformError {
+    Invalid(String),
+}
+
+impl std::fmt::Display for PlatformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlatformError::Invalid(platform) => write!(f, "invalid platform {}", platform),
+        }
+    }
+}
+
+/// Platform of an image as found in manifest lists and OCI indexes
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default)]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Returns the platform of the machine we are running on
+    pub fn host() -> Platform {
+        let (architecture, variant) = match std::env::consts::ARCH {
+            "x86_64" => ("amd64", None),
+            "x86" => ("386", None),
+            "aarch64" => ("arm64", None),
+            "arm" => ("arm", Some("v7")),
+            "powerpc64" if cfg!(target_endian = "little") => ("ppc64le", None),
+            "mips64" if cfg!(target_endian = "little") => ("mips64le", None),
+            arch => (arch, None),
+        };
+
+        Platform {
+            os: String::from(std::env::consts::OS),
+            architecture: String::from(architecture),
+            variant: variant.map(String::from),
+        }
+    }
+
+    /// Parses os/architecture[/variant], the os defaults to linux
+    pub fn parse(platform: &str) -> Result<Platform, PlatformError> {
+        let parts: Vec<&str> = platform.split('/').collect();
+
+        let (os, architecture, variant) = match parts[..] {
+            [arch] => ("linux", arch, None),
+            [os, arch] => (os, arch, None),
+            [os, arch, variant] => (os, arch, Some(variant)),
+            _ => return Err(PlatformError::Invalid(String::from(platform))),
+        };
+
+        if [os, architecture].iter().any(|p| p.is_empty()) || variant == Some("") {
+            return Err(PlatformError::Invalid(String::from(platform)));
+        }
+
+        Ok(Platform {
+            os: os.to_lowercase(),
+            architecture: architecture.to_lowercase(),
+            variant: variant.map(str::to_lowercase),
+        }
+        .normalize())
+    }
+
+    /// Uses the canonical names so equivalent platforms compare equal
+    fn normalize(&self) -> Platform {
+        let architecture = match self.architecture.as_str() {
+            "x86_64" | "x86-64" => "amd64",
+            "aarch64" => "arm64",
+            "i386" => "386",
+            arch => arch,
+        };
+
+        let variant = match (architecture, self.variant.as_deref()) {
+            ("arm64", Some("v8")) | ("amd64", Some("v1")) => None,
+            ("arm", None) => Some("v7"),
+            (_, v) => v,
+        };
+
+        Platform {
+            os: self.os.clone(),
+            architecture: String::from(architecture),
+            variant: variant.map(String::from),
+        }
+    }
+
+    /// Returns how well an image for candidate runs here, None if it cannot.
+    /// Older arm variants run on newer CPUs but are less preferred.
+    fn score(&self, candidate: &Platform) -> Option<u32> {
+        let wanted = self.normalize();
+        let candidate = candidate.normalize();
+
+        if wanted.os != candidate.os || wanted.architecture != candidate.architecture {
+            return None;
+        }
+
+        if wanted.variant == candidate.variant {
+            return Some(u32::MAX);
+        }
+
+        let arm_version = |v: &Option<String>| {
+            v.as_deref()
+                .and_then(|v| v.strip_prefix('v'))
+                .and_then(|v| v.parse::<u32>().ok())
+        };
+
+        match (
+            arm_version(&wanted.variant),
+            arm_version(&candidate.variant),
+        ) {
+            (Some(w), Some(c)) if wanted.architecture == "arm" && c <= w => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Returns the index of the candidate the most suited to this platform
+    pub fn select<'a>(&self, candidates: impl Iterator<Item = &'a Platform>) -> Option<usize> {
+        candidates
+            .enumerate()
+            .filter_map(|(i, c)| self.score(c).map(|s| (s, i)))
+            .max_by_key(|(s, i)| (*s, std::cmp::Reverse(*i)))
+            .map(|(_, i)| i)
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn platform(os: &str, architecture: &str, variant: Option<&str>) -> Platform {
+        Platform {
+            os: String::from(os),
+            architecture: String::from(architecture),
+            variant: variant.map(String::from),
+        }
+    }
+
+    #[test]
+    fn parse_defaults_to_linux() {
+        assert_eq!(
+            Platform::parse("amd64").unwrap(),
+            platform("linux", "amd64", None)
+        );
+    }
+
+    #[test]
+    fn parse_normalizes() {
+        assert_eq!(
+            Platform::parse("Linux/x86_64").unwrap(),
+            platform("linux", "amd64", None)
+        );
+        assert_eq!(
+            Platform::parse("linux/aarch64/v8").unwrap(),
+            platform("linux", "arm64", None)
+        );
+        assert_eq!(
+            Platform::parse("linux/arm").unwrap(),
+            platform("linux", "arm", Some("v7"))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid() {
+        for invalid in ["", "linux/", "/amd64", "linux/arm/", "linux/arm/v7/x"] {
+            assert!(Platform::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn select_exact_match() {
+        let candidates = [
+            platform("linux", "arm64", Some("v8")),
+            platform("linux", "amd64", None),
+            platform("windows", "amd64", None),
+        ];
+
+        let wanted = platform("linux", "amd64", None);
+
+        assert_eq!(wanted.select(candidates.iter()), Some(1));
+    }
+
+    #[test]
+    fn select_prefers_newest_compatible_arm() {
+        let candidates = [
+            platform("linux", "arm", Some("v5")),
+            platform("linux", "arm", Some("v8")),
+            platform("linux", "arm", Some("v6")),
+        ];
+
+        let wanted = platform("linux", "arm", Some("v7"));
+
+        assert_eq!(wanted.select(candidates.iter()), Some(2));
+    }
+
+    #[test]
+    fn select_nothing_compatible() {
+        let candidates = [platform("linux", "s390x", None)];
+
+        let wanted = platform("linux", "amd64", None);
+
+        assert_eq!(wanted.select(candidates.iter()), None);
+    }
+}
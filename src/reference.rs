@@ -0,0 +1,284 @@
+use std::fmt::Display;
+
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+#[derive(Debug)]
+pub enum ReferenceError {
+    Empty,
+    InvalidRegistry(String),
+    InvalidRepository(String),
+    InvalidTag(String),
+    InvalidDigest(String),
+}
+
+impl std::fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReferenceError::Empty => write!(f, "empty image reference"),
+            ReferenceError::InvalidRegistry(registry) => write!(f, "invalid registry {}", registry),
+            ReferenceError::InvalidRepository(repository) => {
+                write!(f, "invalid repository {}", repository)
+            }
+            ReferenceError::InvalidTag(tag) => write!(f, "invalid tag {}", tag),
+            ReferenceError::InvalidDigest(digest) => write!(f, "invalid digest {}", digest),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, ReferenceError>;
+
+/// A parsed image reference: [registry[:port]/]repository[:tag][@digest]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+fn is_valid_registry(registry: &str) -> bool {
+    let (host, port) = match registry.rsplit_once(':') {
+        Some((h, p)) => (h, Some(p)),
+        None => (registry, None),
+    };
+
+    if let Some(p) = port {
+        if p.parse::<u16>().is_err() {
+            return false;
+        }
+    }
+
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+}
+
+/// Path components are lowercase alphanumerics separated by '.', '_', '__' or dashes
+fn is_valid_repository(repository: &str) -> bool {
+    repository.split('/').all(|component| {
+        let bytes = component.as_bytes();
+
+        !bytes.is_empty()
+            && bytes[0].is_ascii_alphanumeric()
+            && bytes[bytes.len() - 1].is_ascii_alphanumeric()
+            && component
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-".contains(c))
+    })
+}
+
+fn is_valid_tag(tag: &str) -> bool {
+    tag.len() <= 128
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+}
+
+fn is_valid_digest(digest: &str) -> bool {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) => hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+impl ImageReference {
+    pub fn parse(image: &str) -> Result<ImageReference> {
+        if image.is_empty() {
+            return Err(ReferenceError::Empty);
+        }
+
+        let (name, digest) = match image.split_once('@') {
+            Some((n, d)) => {
+                if !is_valid_digest(d) {
+                    return Err(ReferenceError::InvalidDigest(String::from(d)));
+                }
+                (n, Some(String::from(d)))
+            }
+            None => (image, None),
+        };
+
+        // The tag is after the last ':' unless it belongs to the registry port
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((n, t)) if !t.contains('/') => {
+                if !is_valid_tag(t) {
+                    return Err(ReferenceError::InvalidTag(String::from(t)));
+                }
+                (n, Some(String::from(t)))
+            }
+            _ => (name, None),
+        };
+
+        // The first component is a registry only if it looks like a host
+        let (registry, repository) = match name.split_once('/') {
+            Some((r, rest)) if r.contains('.') || r.contains(':') || r == "localhost" => {
+                (String::from(r), String::from(rest))
+            }
+            _ => (String::from(DOCKER_HUB), String::from(name)),
+        };
+
+        let registry = match registry.as_str() {
+            "index.docker.io" | DOCKER_HUB_API => String::from(DOCKER_HUB),
+            _ => registry,
+        };
+
+        if !is_valid_registry(&registry) {
+            return Err(ReferenceError::InvalidRegistry(registry));
+        }
+
+        // Official Docker Hub images live in the library namespace
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        if !is_valid_repository(&repository) {
+            return Err(ReferenceError::InvalidRepository(repository));
+        }
+
+        let tag = match (tag, &digest) {
+            (None, None) => Some(String::from(DEFAULT_TAG)),
+            (t, _) => t,
+        };
+
+        Ok(ImageReference {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+
+    /// Returns what identifies the manifest in the registry, the digest if any
+    pub fn version(&self) -> &str {
+        match (&self.digest, &self.tag) {
+            (Some(d), _) => d,
+            (None, Some(t)) => t,
+            (None, None) => DEFAULT_TAG,
+        }
+    }
+
+    /// Returns the base URL of the registry API
+    pub fn api_url(&self) -> String {
+        let host = if self.registry == DOCKER_HUB {
+            DOCKER_HUB_API
+        } else {
+            &self.registry
+        };
+
+        // Only local registries are reached without TLS
+        let scheme = match host.rsplit_once(':').map_or(host, |(h, _)| h) {
+            "localhost" | "127.0.0.1" => "http",
+            _ => "https",
+        };
+
+        format!("{}://{}/v2", scheme, host)
+    }
+}
+
+impl Display for ImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn official_image_is_in_library() {
+        let reference = ImageReference::parse("alpine").unwrap();
+
+        assert_eq!(reference.registry, "docker.io");
+        assert_eq!(reference.repository, "library/alpine");
+        assert_eq!(reference.tag.as_deref(), Some("latest"));
+        assert_eq!(reference.digest, None);
+    }
+
+    #[test]
+    fn docker_hub_aliases() {
+        let reference = ImageReference::parse("index.docker.io/team/img:1.0").unwrap();
+
+        assert_eq!(reference.registry, "docker.io");
+        assert_eq!(reference.repository, "team/img");
+        assert_eq!(reference.tag.as_deref(), Some("1.0"));
+    }
+
+    #[test]
+    fn registry_port_is_not_a_tag() {
+        let reference = ImageReference::parse("registry.example:5000/team/img").unwrap();
+
+        assert_eq!(reference.registry, "registry.example:5000");
+        assert_eq!(reference.repository, "team/img");
+        assert_eq!(reference.tag.as_deref(), Some("latest"));
+    }
+
+    #[test]
+    fn registry_port_tag_and_digest() {
+        let image = format!("host:5000/img:tag@{}", DIGEST);
+        let reference = ImageReference::parse(&image).unwrap();
+
+        assert_eq!(reference.registry, "host:5000");
+        assert_eq!(reference.repository, "img");
+        assert_eq!(reference.tag.as_deref(), Some("tag"));
+        assert_eq!(reference.digest.as_deref(), Some(DIGEST));
+        assert_eq!(reference.version(), DIGEST);
+    }
+
+    #[test]
+    fn digest_without_tag() {
+        let reference = ImageReference::parse(&format!("alpine@{}", DIGEST)).unwrap();
+
+        assert_eq!(reference.tag, None);
+        assert_eq!(reference.version(), DIGEST);
+    }
+
+    #[test]
+    fn localhost_is_a_registry() {
+        let reference = ImageReference::parse("localhost/img").unwrap();
+
+        assert_eq!(reference.registry, "localhost");
+        assert_eq!(reference.api_url(), "http://localhost/v2");
+    }
+
+    #[test]
+    fn invalid_references() {
+        assert!(matches!(
+            ImageReference::parse(""),
+            Err(ReferenceError::Empty)
+        ));
+        assert!(matches!(
+            ImageReference::parse("Alpine"),
+            Err(ReferenceError::InvalidRepository(_))
+        ));
+        assert!(matches!(
+            ImageReference::parse("alpine:-latest"),
+            Err(ReferenceError::InvalidTag(_))
+        ));
+        assert!(matches!(
+            ImageReference::parse("alpine@sha256:1234"),
+            Err(ReferenceError::InvalidDigest(_))
+        ));
+        assert!(matches!(
+            ImageReference::parse("host:port/img"),
+            Err(ReferenceError::InvalidRegistry(_))
+        ));
+    }
+}
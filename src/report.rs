@@ -0,0 +1,204 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    process::ExitStatus,
+    time::{Duration, Instant},
+};
+
+use crate::cgroup::{CgroupV2, CpuStat, IoStat, MemoryEvents, PeakWatch};
+use crate::units::format_size;
+use crate::verdict::Verdict;
+
+/// How the usage of a run is printed on stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Human,
+    Json,
+}
+
+impl ReportFormat {
+    pub fn parse(format: &str) -> Option<ReportFormat> {
+        match format {
+            "human" => Some(ReportFormat::Human),
+            "json" => Some(ReportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// What a peak covers, a named sandbox cannot always measure a single run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeakScope {
+    /// The program and its children only
+    Run,
+    /// Every program run in the sandbox since its creation
+    Sandbox,
+}
+
+/// Counters of the cgroup read before the program starts, a named sandbox
+/// keeps those of the previous programs
+pub struct Snapshot {
+    started: Instant,
+    cpu: CpuStat,
+    memory_events: MemoryEvents,
+    io: Vec<IoStat>,
+    memory_peak: Option<PeakWatch>,
+    /// The cgroup was created for this run, its peaks are the program's
+    fresh: bool,
+}
+
+impl Snapshot {
+    pub fn take(cgroup: &CgroupV2, fresh: bool) -> Snapshot {
+        Snapshot {
+            started: Instant::now(),
+            cpu: cgroup.cpu_stat().unwrap_or_default(),
+            memory_events: cgroup.memory_events().unwrap_or_default(),
+            io: cgroup.io_stat().unwrap_or_default(),
+            memory_peak: if fresh {
+                None
+            } else {
+                cgroup.watch_memory_peak()
+            },
+            fresh,
+        }
+    }
+
+    fn peak_scope(&self, resettable: bool) -> PeakScope {
+        if self.fresh || resettable {
+            PeakScope::Run
+        } else {
+            PeakScope::Sandbox
+        }
+    }
+}
+
+/// What the program consumed
+#[derive(Debug, Serialize)]
+pub struct Report {
+    #[serde(skip)]
+    pub verdict: Verdict,
+    #[serde(rename = "verdict")]
+    pub description: String,
+    pub exit_code: i32,
+    pub oom_killed: bool,
+    pub wall_time_usec: u64,
+    pub cpu: CpuStat,
+    /// Bytes, None if the kernel does not track it
+    pub memory_peak: Option<u64>,
+    pub memory_peak_scope: PeakScope,
+    pub memory_current: Option<u64>,
+    pub memory_stat: BTreeMap<String, u64>,
+    pub memory_events: MemoryEvents,
+    pub pids_peak: Option<u64>,
+    pub pids_peak_scope: PeakScope,
+    pub io: Vec<IoStat>,
+}
+
+impl Report {
+    /// Reads the counters once the program exited
+    pub fn collect(cgroup: &CgroupV2, before: &Snapshot, status: ExitStatus) -> Report {
+        let wall_time = before.started.elapsed();
+
+        let memory_events = cgroup.memory_events().unwrap_or_default();
+
+        let verdict = Verdict::judge(status, &before.memory_events, &memory_events);
+
+        let io = cgroup
+            .io_stat()
+            .unwrap_or_default()
+            .iter()
+            .map(|stat| stat.since(&before.io))
+            .collect();
+
+        let memory_peak = match &before.memory_peak {
+            Some(watch) => watch.read().ok(),
+            None => cgroup.memory_peak().unwrap_or_default(),
+        };
+
+        Report {
+            verdict,
+            description: verdict.to_string(),
+            exit_code: verdict.exit_code(),
+            oom_killed: verdict == Verdict::OutOfMemory,
+            wall_time_usec: wall_time.as_micros() as u64,
+            cpu: cgroup.cpu_stat().unwrap_or_default().since(&before.cpu),
+            memory_peak,
+            memory_peak_scope: before.peak_scope(before.memory_peak.is_some()),
+            memory_current: cgroup.memory_current().unwrap_or_default(),
+            memory_stat: cgroup.memory_stat().unwrap_or_default(),
+            memory_events: memory_events.since(&before.memory_events),
+            pids_peak: cgroup.pids_peak().unwrap_or_default(),
+            pids_peak_scope: before.peak_scope(false),
+            io,
+        }
+    }
+
+    /// Printed on stderr, stdout belongs to the program
+    pub fn print(&self, format: ReportFormat) {
+        match format {
+            ReportFormat::Human => eprint!("{}", self.human()),
+            ReportFormat::Json => match serde_json::to_string(self) {
+                Ok(json) => eprintln!("{}", json),
+                Err(e) => eprintln!("moulinette: cannot serialize the report: {}", e),
+            },
+        }
+    }
+
+    fn human(&self) -> String {
+        let seconds = |usec: u64| Duration::from_micros(usec).as_secs_f64();
+        let size = |bytes: Option<u64>| match bytes {
+            Some(b) => format_size(b),
+            None => String::from("unknown"),
+        };
+        let stat = |key: &str| size(self.memory_stat.get(key).copied());
+        let scope = |scope: PeakScope| match scope {
+            PeakScope::Run => "",
+            PeakScope::Sandbox => " (whole sandbox)",
+        };
+
+        let mut lines: Vec<String> = vec![
+            format!("moulinette: program {}", self.description),
+            format!("  wall time  {:.3} s", seconds(self.wall_time_usec)),
+            format!(
+                "  cpu time   {:.3} s (user {:.3} s, system {:.3} s), throttled {:.3} s",
+                seconds(self.cpu.usage_usec),
+                seconds(self.cpu.user_usec),
+                seconds(self.cpu.system_usec),
+                seconds(self.cpu.throttled_usec)
+            ),
+            format!(
+                "  memory     peak {}{}, anon {}, file {}",
+                size(self.memory_peak),
+                scope(self.memory_peak_scope),
+                stat("anon"),
+                stat("file")
+            ),
+            format!(
+                "  oom        {} kills, {} times above high",
+                self.memory_events.oom_kill, self.memory_events.high
+            ),
+        ];
+
+        if let Some(peak) = self.pids_peak {
+            lines.push(format!(
+                "  processes  peak {}{}",
+                peak,
+                scope(self.pids_peak_scope)
+            ));
+        }
+
+        for io in &self.io {
+            lines.push(format!(
+                "  io {}   read {} ({} ops), written {} ({} ops)",
+                io.device,
+                format_size(io.rbytes),
+                io.rios,
+                format_size(io.wbytes),
+                io.wios
+            ));
+        }
+
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
@@ -0,0 +1,241 @@
+use log::{info, warn};
+use nix::unistd::{setgid, setgroups, setuid, Gid, Uid};
+use std::{env, fs, io, os::unix::process::CommandExt, process::Command};
+
+use crate::docker_image::ImageConfig;
+
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    NoCommand,
+    InvalidUser(String),
+    IOError(std::io::Error),
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::NoCommand => write!(f, "no command to run"),
+            RuntimeError::InvalidUser(user) => write!(f, "unknown user {}", user),
+            RuntimeError::IOError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, RuntimeError>;
+
+/// Command line settings taking precedence over the image config
+#[derive(Debug, Default)]
+pub struct Overrides {
+    /// Replaces the entrypoint of the image, an empty one removes it
+    pub entrypoint: Option<String>,
+    /// Replaces the Cmd of the image when not empty
+    pub command: Vec<String>,
+    /// KEY=VALUE, or KEY to take the value from our environment
+    pub env: Vec<String>,
+    pub working_dir: Option<String>,
+    /// user[:group], by name or id
+    pub user: Option<String>,
+}
+
+struct User {
+    uid: u32,
+    gid: u32,
+    home: Option<String>,
+}
+
+/// Returns the entries of /etc/passwd or /etc/group of the sandbox as fields
+fn read_database(path: &str) -> Vec<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => content
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| l.split(':').map(String::from).collect())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Resolves user[:group] against the databases of the sandbox, as docker does
+fn resolve_user(spec: &str) -> Result<User> {
+    let (user, group) = match spec.split_once(':') {
+        Some((u, g)) => (u, Some(g)),
+        None => (spec, None),
+    };
+
+    let passwd = read_database("/etc/passwd");
+
+    let entry = passwd
+        .iter()
+        .find(|e| e.len() >= 6 && (e[0] == user || e[2] == user));
+
+    let (uid, mut gid, home) = match (entry, user.parse::<u32>()) {
+        (Some(e), _) => {
+            let uid = e[2].parse::<u32>().ok();
+            let gid = e[3].parse::<u32>().ok();
+            match (uid, gid) {
+                (Some(u), Some(g)) => (u, g, Some(e[5].clone())),
+                _ => return Err(RuntimeError::InvalidUser(String::from(spec))),
+            }
+        }
+        // A numeric user does not need to exist in the image
+        (None, Ok(uid)) => (uid, 0, None),
+        (None, Err(_)) => return Err(RuntimeError::InvalidUser(String::from(spec))),
+    };
+
+    if let Some(group) = group {
+        let groups = read_database("/etc/group");
+
+        let entry = groups.iter().find(|e| e.len() >= 3 && e[0] == group);
+
+        gid = match (entry, group.parse::<u32>()) {
+            (Some(e), _) => match e[2].parse::<u32>() {
+                Ok(g) => g,
+                Err(_) => return Err(RuntimeError::InvalidUser(String::from(spec))),
+            },
+            (None, Ok(g)) => g,
+            (None, Err(_)) => return Err(RuntimeError::InvalidUser(String::from(spec))),
+        };
+    }
+
+    Ok(User { uid, gid, home })
+}
+
+/// Adds or replaces a KEY=VALUE entry in an environment
+fn set_env(environment: &mut Vec<(String, String)>, key: &str, value: &str) {
+    match environment.iter_mut().find(|(k, _)| k == key) {
+        Some(entry) => entry.1 = String::from(value),
+        None => environment.push((String::from(key), String::from(value))),
+    }
+}
+
+fn has_env(environment: &[(String, String)], key: &str) -> bool {
+    environment.iter().any(|(k, _)| k == key)
+}
+
+/// Builds the command of the sandboxed program, must run inside the new rootfs.
+/// enter runs in the child before it drops its privileges.
+pub fn build_command<F>(
+    config: &ImageConfig,
+    overrides: &Overrides,
+    hostname: &str,
+    enter: F,
+) -> Result<Command>
+where
+    F: FnMut() -> io::Result<()> + Send + Sync + 'static,
+{
+    // Like docker, overriding the entrypoint also drops the Cmd of the image
+    let (entrypoint, cmd) = match &overrides.entrypoint {
+        Some(e) if e.is_empty() => (Vec::new(), None),
+        Some(e) => (vec![e.clone()], None),
+        None => (
+            config.entrypoint.clone().unwrap_or_default(),
+            config.cmd.as_ref(),
+        ),
+    };
+
+    let mut argv: Vec<String> = entrypoint;
+
+    if !overrides.command.is_empty() {
+        argv.extend(overrides.command.iter().cloned());
+    } else if let Some(cmd) = cmd {
+        argv.extend(cmd.iter().cloned());
+    }
+
+    if argv.is_empty() {
+        return Err(RuntimeError::NoCommand);
+    }
+
+    info!(target:"runtime", "command {:?}", argv);
+
+    let mut environment: Vec<(String, String)> = Vec::new();
+
+    for entry in config.env.iter().flatten() {
+        match entry.split_once('=') {
+            Some((k, v)) => set_env(&mut environment, k, v),
+            None => warn!(target:"runtime", "ignoring image env {}", entry),
+        }
+    }
+
+    for entry in &overrides.env {
+        match entry.split_once('=') {
+            Some((k, v)) => set_env(&mut environment, k, v),
+            None => {
+                if let Ok(v) = env::var(entry) {
+                    set_env(&mut environment, entry, &v);
+                }
+            }
+        }
+    }
+
+    if !has_env(&environment, "PATH") {
+        set_env(&mut environment, "PATH", DEFAULT_PATH);
+    }
+
+    // Keep interactive shells usable
+    if let (false, Ok(term)) = (has_env(&environment, "TERM"), env::var("TERM")) {
+        set_env(&mut environment, "TERM", &term);
+    }
+
+    set_env(&mut environment, "HOSTNAME", hostname);
+
+    let mut command = Command::new(&argv[0]);
+
+    command.args(&argv[1..]);
+
+    let user = match overrides.user.as_ref().or(config.user.as_ref()) {
+        Some(spec) if !spec.is_empty() => Some(resolve_user(spec)?),
+        _ => None,
+    };
+
+    if !has_env(&environment, "HOME") {
+        let home = match &user {
+            Some(User { home: Some(h), .. }) => h.as_str(),
+            Some(User { uid: 0, .. }) | None => "/root",
+            Some(_) => "/",
+        };
+
+        set_env(&mut environment, "HOME", home);
+    }
+
+    // enter needs root, so the user is switched by hand afterwards, std
+    // would switch it before any hook
+    unsafe {
+        command.pre_exec(enter);
+    }
+
+    if let Some(user) = &user {
+        info!(target:"runtime", "user {}:{}", user.uid, user.gid);
+
+        let (uid, gid) = (Uid::from_raw(user.uid), Gid::from_raw(user.gid));
+
+        unsafe {
+            command.pre_exec(move || {
+                setgroups(&[])?;
+                setgid(gid)?;
+                setuid(uid)?;
+                Ok(())
+            });
+        }
+    }
+
+    command.env_clear().envs(environment);
+
+    let working_dir = overrides
+        .working_dir
+        .as_ref()
+        .or(config.working_dir.as_ref())
+        .filter(|w| !w.is_empty());
+
+    if let Some(working_dir) = working_dir {
+        // Docker creates a missing working directory
+        if let Err(e) = fs::create_dir_all(working_dir) {
+            return Err(RuntimeError::IOError(e));
+        }
+
+        command.current_dir(working_dir);
+    }
+
+    Ok(command)
+}
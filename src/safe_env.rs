@@ -1,17 +1,29 @@
-use fs_extra::dir::CopyOptions;
-use log::{error, info, warn};
+use log::{error, info};
 use nix::{
     errno::Errno,
     mount::{mount, umount2, MntFlags, MsFlags},
+    sys::{
+        stat::{makedev, mknod, Mode, SFlag},
+        statvfs::{statvfs, FsFlags},
+    },
     unistd::pivot_root,
 };
 use std::{
-    fs,
+    ffi::OsString,
+    fs::{self, File},
+    os::unix::{
+        ffi::OsStringExt,
+        fs::{symlink, PermissionsExt},
+        io::AsRawFd,
+    },
     path::{Path, PathBuf},
 };
 use tempdir::TempDir;
 
-use crate::docker_image;
+use crate::docker_image::{self, DockerError, ImageConfig, PullOptions};
+use crate::inject::{self, CopyEntry, InjectError};
+use crate::layer::{self, LayerError};
+use crate::local_image::{self, LocalImageError};
 
 #[derive(Debug)]
 pub enum SafeEnvError {
@@ -19,38 +31,261 @@ pub enum SafeEnvError {
     Mount(Errno),
     Umount(Errno),
     PivotRoot(Errno),
+    Mknod(Errno),
+    Docker(DockerError),
+    LocalImage(LocalImageError),
+    Inject(InjectError),
+    Layer(LayerError),
+}
+
+impl std::fmt::Display for SafeEnvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SafeEnvError::IOError(e) => write!(f, "{}", e),
+            SafeEnvError::Mount(e) => write!(f, "mount: {}", e),
+            SafeEnvError::Umount(e) => write!(f, "umount: {}", e),
+            SafeEnvError::PivotRoot(e) => write!(f, "pivot_root: {}", e),
+            SafeEnvError::Mknod(e) => write!(f, "mknod: {}", e),
+            SafeEnvError::Docker(e) => write!(f, "{}", e),
+            SafeEnvError::LocalImage(e) => write!(f, "{}", e),
+            SafeEnvError::Inject(e) => write!(f, "{}", e),
+            SafeEnvError::Layer(e) => write!(f, "{}", e),
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, SafeEnvError>;
 
-fn mount_workdir(src: &Path) -> Result<()> {
-    let workdir_path: PathBuf = PathBuf::from("/").join("home").join("student");
+/// Character devices of the sandbox /dev, as (name, major, minor)
+const DEVICES: [(&str, u64, u64); 6] = [
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+const DEV_SYMLINKS: [(&str, &str); 5] = [
+    ("ptmx", "pts/ptmx"),
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
+
+/// Kernel information the sandbox must not see
+const MASKED_PATHS: [&str; 12] = [
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/sysrq-trigger",
+    "/sys/firmware",
+    "/sys/devices/virtual/powercap",
+];
+
+/// Kernel settings the sandbox may read but not change
+const READ_ONLY_PATHS: [&str; 4] = ["/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys"];
+
+const STUDENT_WORKDIR: &str = "/home/student";
+
+const DEFAULT_TMPFS_SIZE: &str = "64m";
+/// Directories programs expect to write to even with a read-only rootfs
+const READ_ONLY_TMPFS: [&str; 2] = ["/tmp", "/run"];
+
+/// A host path bound in the sandbox, from src[:dst[:ro|rw]]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindMount {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub read_only: bool,
+}
+
+impl BindMount {
+    /// A lone directory is the student workdir, as before
+    pub fn parse(spec: &str) -> Option<BindMount> {
+        let parts: Vec<&str> = spec.split(':').collect();
+
+        let (source, target, mode) = match parts[..] {
+            [src] => (src, STUDENT_WORKDIR, "rw"),
+            [src, dst] => (src, dst, "rw"),
+            [src, dst, mode] => (src, dst, mode),
+            _ => return None,
+        };
+
+        let read_only = match mode {
+            "ro" => true,
+            "rw" => false,
+            _ => return None,
+        };
+
+        if source.is_empty() || !target.starts_with('/') {
+            return None;
+        }
+
+        Some(BindMount {
+            source: PathBuf::from(source),
+            target: PathBuf::from(target),
+            read_only,
+        })
+    }
+}
+
+/// Resolves a path of the sandbox before switching root. Once switched, the
+/// symlinks of the image could lead to the host through the old root.
+fn resolve_target(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    match layer::resolve_in_root(rootfs, path) {
+        Ok(p) => Ok(Path::new("/").join(p)),
+        Err(e) => {
+            error!(target:"safe_env", "cannot resolve {:?}", path);
+            Err(SafeEnvError::Layer(e))
+        }
+    }
+}
+
+/// mountinfo writes spaces, tabs, newlines and backslashes as \ooo
+fn unescape_mount_point(point: &str) -> PathBuf {
+    let bytes = point.as_bytes();
+    let mut unescaped: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .and_then(|o| std::str::from_utf8(o).ok())
+            .and_then(|o| u8::from_str_radix(o, 8).ok());
+
+        match (bytes[i], octal) {
+            (b'\\', Some(c)) => {
+                unescaped.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                unescaped.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    PathBuf::from(OsString::from_vec(unescaped))
+}
+
+/// Mount points at or below path, the path first
+fn mounts_below(path: &Path) -> Result<Vec<PathBuf>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").map_err(SafeEnvError::IOError)?;
+
+    let mut points: Vec<PathBuf> = mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_mount_point)
+        .filter(|p| p.starts_with(path))
+        .collect();
 
-    info!(target:"workdir", "{:?}", src);
+    points.sort();
+    points.dedup();
 
-    if let Err(e) = fs::create_dir_all(&workdir_path) {
-        error!(target:"workdir", "create_dir failed");
+    Ok(points)
+}
+
+/// Flags of a mount a remount would otherwise clear
+fn kept_flags(path: &Path) -> MsFlags {
+    let flags = match statvfs(path) {
+        Ok(s) => s.flags(),
+        Err(_) => return MsFlags::empty(),
+    };
+
+    [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ]
+    .iter()
+    .filter(|(st, _)| flags.contains(*st))
+    .fold(MsFlags::empty(), |acc, (_, ms)| acc | *ms)
+}
+
+/// Remounts a recursive bind read-only. A remount only changes a single
+/// mount, even with MS_REC, so the mounts below it are remounted one by one.
+fn remount_read_only(path: &Path) -> Result<()> {
+    for point in mounts_below(path)? {
+        if let Err(e) = mount(
+            Option::<&str>::None,
+            &point,
+            Option::<&str>::None,
+            MsFlags::MS_BIND
+                | MsFlags::MS_REMOUNT
+                | MsFlags::MS_RDONLY
+                | MsFlags::MS_REC
+                | kept_flags(&point),
+            Option::<&str>::None,
+        ) {
+            error!(target:"bind", "read-only remount of {:?} failed", point);
+            return Err(SafeEnvError::Mount(e));
+        }
+    }
+
+    Ok(())
+}
+
+/// Binds source, reached through the old root, on its target in the sandbox.
+/// The target was resolved in the rootfs before switching root.
+fn mount_bind(bind: &BindMount, oldroot: &Path) -> Result<()> {
+    let src = oldroot.join(bind.source.strip_prefix("/").unwrap_or(&bind.source));
+
+    info!(target:"bind", "{:?} on {:?}", bind.source, bind.target);
+
+    // Files can be bound too, their mount point must be a file
+    let created = if src.is_dir() {
+        fs::create_dir_all(&bind.target)
+    } else {
+        let parent = bind.target.parent().unwrap_or_else(|| Path::new("/"));
+        fs::create_dir_all(parent).and_then(|_| {
+            fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&bind.target)
+                .map(|_| ())
+        })
+    };
+
+    if let Err(e) = created {
+        error!(target:"bind", "cannot create {:?}", bind.target);
         return Err(SafeEnvError::IOError(e));
     }
 
     if let Err(e) = mount(
-        Some(src),
-        &workdir_path,
-        Option::Some("rw"),
-        MsFlags::MS_BIND,
+        Some(&src),
+        &bind.target,
+        Option::<&str>::None,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
         Option::<&str>::None,
     ) {
-        error!(target:"workdir", "mount failed");
+        error!(target:"bind", "mount failed");
         return Err(SafeEnvError::Mount(e));
     }
 
-    info!(target:"workdir", "mounted");
+    // The flags of a bind mount are only taken into account by a remount
+    if bind.read_only {
+        remount_read_only(&bind.target)?;
+    }
+
+    info!(target:"bind", "mounted");
 
     Ok(())
 }
 
-fn switch_root(rootfs: &Path) -> Result<&Path> {
-    // Check if we can mout /
+/// Stops our mounts from propagating to the host, must run before any of them
+fn make_mounts_private() -> Result<()> {
     if let Err(e) = mount(
         Option::<&str>::None,
         "/",
@@ -64,6 +299,73 @@ fn switch_root(rootfs: &Path) -> Result<&Path> {
 
     info!(target:"/", "mounted");
 
+    Ok(())
+}
+
+/// Mounts lower read-only below an overlay whose changes go to diff_dir,
+/// or to a tmpfs discarded with the sandbox. Returns the merged rootfs.
+fn mount_overlay(lower: &Path, tmp_dir: &Path, diff_dir: Option<&Path>) -> Result<PathBuf> {
+    let lower = match fs::canonicalize(lower) {
+        Ok(l) => l,
+        Err(e) => {
+            error!(target:"overlay", "{:?} not found", lower);
+            return Err(SafeEnvError::IOError(e));
+        }
+    };
+
+    let changes = match diff_dir {
+        Some(d) => PathBuf::from(d),
+        None => {
+            if let Err(e) = mount(
+                Option::Some("tmpfs"),
+                tmp_dir,
+                Option::Some("tmpfs"),
+                MsFlags::empty(),
+                Option::<&str>::None,
+            ) {
+                error!(target:"overlay", "tmpfs mount failed");
+                return Err(SafeEnvError::Mount(e));
+            }
+
+            PathBuf::from(tmp_dir)
+        }
+    };
+
+    let upper = changes.join("upper");
+    let work = changes.join("work");
+    let merged = tmp_dir.join("rootfs");
+
+    for dir in [&upper, &work, &merged] {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!(target:"overlay", "cannot create {:?}", dir);
+            return Err(SafeEnvError::IOError(e));
+        }
+    }
+
+    let data = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.display(),
+        upper.display(),
+        work.display()
+    );
+
+    if let Err(e) = mount(
+        Option::Some("overlay"),
+        &merged,
+        Option::Some("overlay"),
+        MsFlags::empty(),
+        Option::Some(data.as_str()),
+    ) {
+        error!(target:"overlay", "mount failed");
+        return Err(SafeEnvError::Mount(e));
+    }
+
+    info!(target:"overlay", "{:?} mounted, changes in {:?}", lower, upper);
+
+    Ok(merged)
+}
+
+fn switch_root(rootfs: &Path) -> Result<&Path> {
     // Mount tmpdir for pivot root
     if let Err(e) = mount(
         Option::Some(rootfs),
@@ -103,7 +405,7 @@ fn switch_root(rootfs: &Path) -> Result<&Path> {
     Ok(Path::new("/oldrootfs"))
 }
 
-fn mount_os_fs(oldroot: &Path) -> Result<()> {
+fn mount_os_fs() -> Result<()> {
     if let Err(e) = mount(
         Option::<&str>::None,
         "/proc",
@@ -117,22 +419,223 @@ fn mount_os_fs(oldroot: &Path) -> Result<()> {
 
     info!(target:"proc", "mounted");
 
+    mount_dev()?;
+
+    mount_dev_fs(
+        "sysfs",
+        "/sys",
+        MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        "",
+    )?;
+
+    info!(target:"sys", "mounted");
+
+    mask_kernel_paths()?;
+
+    Ok(())
+}
+
+/// Binds path on itself then remounts it read-only
+fn bind_read_only(path: &Path) -> Result<()> {
     if let Err(e) = mount(
-        Option::Some(&oldroot.join("dev")),
-        "/dev",
+        Option::Some(path),
+        path,
         Option::<&str>::None,
-        MsFlags::MS_MOVE,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
         Option::<&str>::None,
     ) {
-        error!(target:"dev", "mount failed");
+        error!(target:"mask", "bind of {:?} failed", path);
+        return Err(SafeEnvError::Mount(e));
+    }
+
+    remount_read_only(path)
+}
+
+/// Hides and protects the kernel state, as OCI runtimes do by default
+fn mask_kernel_paths() -> Result<()> {
+    for path in MASKED_PATHS.iter().map(Path::new) {
+        let result = match fs::symlink_metadata(path) {
+            // Directories are hidden below an empty read-only tmpfs
+            Ok(m) if m.is_dir() => mount(
+                Option::Some("tmpfs"),
+                path,
+                Option::Some("tmpfs"),
+                MsFlags::MS_RDONLY,
+                Option::<&str>::None,
+            ),
+            Ok(_) => mount(
+                Option::Some("/dev/null"),
+                path,
+                Option::<&str>::None,
+                MsFlags::MS_BIND,
+                Option::<&str>::None,
+            ),
+            // Depends on the kernel configuration
+            Err(_) => continue,
+        };
+
+        if let Err(e) = result {
+            error!(target:"mask", "cannot mask {:?}", path);
+            return Err(SafeEnvError::Mount(e));
+        }
+
+        info!(target:"mask", "{:?} masked", path);
+    }
+
+    for path in READ_ONLY_PATHS.iter().map(Path::new) {
+        if path.exists() {
+            bind_read_only(path)?;
+            info!(target:"mask", "{:?} read-only", path);
+        }
+    }
+
+    Ok(())
+}
+
+fn mount_dev_fs(source: &str, target: &str, flags: MsFlags, data: &str) -> Result<()> {
+    if let Err(e) = fs::create_dir_all(target) {
+        error!(target:"dev", "cannot create {}", target);
+        return Err(SafeEnvError::IOError(e));
+    }
+
+    if let Err(e) = mount(
+        Option::Some(source),
+        target,
+        Option::Some(source),
+        flags,
+        Option::Some(data),
+    ) {
+        error!(target:"dev", "mount of {} failed", target);
         return Err(SafeEnvError::Mount(e));
     }
 
+    Ok(())
+}
+
+/// Builds a /dev holding only the devices programs expect, never the host ones
+fn mount_dev() -> Result<()> {
+    mount_dev_fs(
+        "tmpfs",
+        "/dev",
+        MsFlags::MS_NOSUID | MsFlags::MS_STRICTATIME,
+        "mode=755,size=64k",
+    )?;
+
+    for (name, major, minor) in DEVICES {
+        let path = Path::new("/dev").join(name);
+
+        if let Err(e) = mknod(
+            &path,
+            SFlag::S_IFCHR,
+            Mode::from_bits_truncate(0o666),
+            makedev(major, minor),
+        ) {
+            error!(target:"dev", "mknod {:?} failed", path);
+            return Err(SafeEnvError::Mknod(e));
+        }
+
+        // mknod is subject to the umask
+        if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(0o666)) {
+            return Err(SafeEnvError::IOError(e));
+        }
+    }
+
+    // A private instance, the ptys of the host are not visible
+    mount_dev_fs(
+        "devpts",
+        "/dev/pts",
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        "newinstance,ptmxmode=0666,mode=0620",
+    )?;
+
+    mount_dev_fs(
+        "tmpfs",
+        "/dev/shm",
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        "mode=1777,size=64m",
+    )?;
+
+    for (link, target) in DEV_SYMLINKS {
+        if let Err(e) = symlink(target, Path::new("/dev").join(link)) {
+            error!(target:"dev", "cannot create /dev/{}", link);
+            return Err(SafeEnvError::IOError(e));
+        }
+    }
+
     info!(target:"dev", "mounted");
 
     Ok(())
 }
 
+/// A tmpfs mounted in the sandbox, from path[:size] where size is understood by tmpfs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TmpfsMount {
+    pub path: PathBuf,
+    pub size: String,
+}
+
+impl TmpfsMount {
+    pub fn parse(spec: &str) -> Option<TmpfsMount> {
+        let (path, size) = match spec.split_once(':') {
+            Some((p, s)) => (p, s),
+            None => (spec, DEFAULT_TMPFS_SIZE),
+        };
+
+        // Sizes such as 64m, 1g or 10%, checked by the kernel
+        if !path.starts_with('/') || size.is_empty() || size.contains(',') {
+            return None;
+        }
+
+        Some(TmpfsMount {
+            path: PathBuf::from(path),
+            size: String::from(size),
+        })
+    }
+}
+
+/// The path was resolved in the rootfs before switching root
+fn mount_tmpfs(tmpfs: &TmpfsMount) -> Result<()> {
+    if let Err(e) = fs::create_dir_all(&tmpfs.path) {
+        error!(target:"tmpfs", "cannot create {:?}", tmpfs.path);
+        return Err(SafeEnvError::IOError(e));
+    }
+
+    let data = format!("size={},mode=1777", tmpfs.size);
+
+    if let Err(e) = mount(
+        Option::Some("tmpfs"),
+        &tmpfs.path,
+        Option::Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Option::Some(data.as_str()),
+    ) {
+        error!(target:"tmpfs", "mount of {:?} failed", tmpfs.path);
+        return Err(SafeEnvError::Mount(e));
+    }
+
+    info!(target:"tmpfs", "{:?} mounted, size {}", tmpfs.path, tmpfs.size);
+
+    Ok(())
+}
+
+/// Makes the rootfs itself read-only, the mounts on top of it are kept as is
+fn remount_root_read_only() -> Result<()> {
+    if let Err(e) = mount(
+        Option::<&str>::None,
+        "/",
+        Option::<&str>::None,
+        MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+        Option::<&str>::None,
+    ) {
+        error!(target:"read_only", "remount failed");
+        return Err(SafeEnvError::Mount(e));
+    }
+
+    info!(target:"read_only", "/ remounted read-only");
+
+    Ok(())
+}
+
 fn clean_oldrootfs(oldroot: &Path) -> Result<()> {
     if let Err(e) = umount2(oldroot, MntFlags::MNT_DETACH) {
         error!(target:"oldroot", "unmount failed");
@@ -149,7 +652,119 @@ fn clean_oldrootfs(oldroot: &Path) -> Result<()> {
     Ok(())
 }
 
-pub fn create_environment(workdir: Option<&String>, rootfs: Option<&String>) -> Result<()> {
+/// Where the rootfs of the sandbox comes from
+#[derive(Debug)]
+pub enum RootfsSource {
+    /// Image reference pulled from a registry, or taken from the cache
+    Registry(String),
+    /// Directory of the host, mounted read-only below an overlay
+    Directory(PathBuf),
+    /// OCI image layout directory or docker save archive
+    LocalImage(PathBuf),
+}
+
+/// How the filesystem of the sandbox is built
+#[derive(Debug, Default)]
+pub struct EnvironmentOptions {
+    /// Host paths bound in the sandbox, in order
+    pub binds: Vec<BindMount>,
+    pub rootfs: Option<RootfsSource>,
+    /// Empty host directory keeping the changes made to a Directory rootfs
+    pub diff_dir: Option<PathBuf>,
+    /// Remounts the rootfs read-only once the sandbox is set up
+    pub read_only: bool,
+    /// Writable tmpfs, /tmp and /run are added to read-only rootfs
+    pub tmpfs: Vec<TmpfsMount>,
+    /// Host files copied into the rootfs, such as the test suite
+    pub copies: Vec<CopyEntry>,
+}
+
+impl EnvironmentOptions {
+    /// Returns the tmpfs to mount, with the defaults of read-only rootfs
+    fn tmpfs_mounts(&self) -> Vec<TmpfsMount> {
+        let mut mounts = self.tmpfs.clone();
+
+        if self.read_only {
+            for path in READ_ONLY_TMPFS {
+                if !mounts.iter().any(|m| m.path == Path::new(path)) {
+                    mounts.push(TmpfsMount {
+                        path: PathBuf::from(path),
+                        size: String::from(DEFAULT_TMPFS_SIZE),
+                    });
+                }
+            }
+        }
+
+        mounts
+    }
+}
+
+/// Fills the rootfs from its source, returns the runtime settings of the image
+/// and where the rootfs is
+fn populate_rootfs(
+    source: &RootfsSource,
+    tmp_dir: &Path,
+    diff_dir: Option<&Path>,
+    pull_options: &PullOptions,
+) -> Result<(ImageConfig, PathBuf)> {
+    match source {
+        RootfsSource::Registry(image) => match docker_image::download(image, tmp_dir, pull_options)
+        {
+            Ok(c) => Ok((c, PathBuf::from(tmp_dir))),
+            Err(e) => {
+                error!(target:"rootfs", "cannot pull {}: {:?}", image, e);
+                Err(SafeEnvError::Docker(e))
+            }
+        },
+        RootfsSource::LocalImage(path) => match local_image::load(path, tmp_dir, pull_options) {
+            Ok(c) => Ok((c, PathBuf::from(tmp_dir))),
+            Err(e) => {
+                error!(target:"rootfs", "cannot load {:?}: {:?}", path, e);
+                Err(SafeEnvError::LocalImage(e))
+            }
+        },
+        // A rootfs of the host has no runtime settings
+        RootfsSource::Directory(path) => Ok((
+            ImageConfig::default(),
+            mount_overlay(path, tmp_dir, diff_dir)?,
+        )),
+    }
+}
+
+/// The filesystem of the sandbox, once we are inside of it
+#[derive(Debug)]
+pub struct Environment {
+    pub image_config: ImageConfig,
+    /// Directory of the host the rootfs was built in, unreachable from the sandbox
+    pub host_dir: PathBuf,
+    /// Parent of host_dir, opened before switching root
+    host_parent: File,
+}
+
+impl Environment {
+    /// Removes the directory the rootfs was built in, from inside the sandbox.
+    /// Nothing may run in the sandbox anymore.
+    pub fn remove_host_dir(&self) -> Result<()> {
+        let name = self.host_dir.file_name().unwrap_or_default();
+        let path = PathBuf::from(format!("/proc/self/fd/{}", self.host_parent.as_raw_fd()));
+
+        if let Err(e) = fs::remove_dir_all(path.join(name)) {
+            error!(target:"safe_env", "cannot remove {:?}", self.host_dir);
+            return Err(SafeEnvError::IOError(e));
+        }
+
+        info!(target:"safe_env", "{:?} removed", self.host_dir);
+
+        Ok(())
+    }
+}
+
+pub fn create_environment(
+    options: &EnvironmentOptions,
+    pull_options: &PullOptions,
+) -> Result<Environment> {
+    make_mounts_private()?;
+
     // Create a temp dir to be used as root file system
     let tmp_dir: TempDir = match TempDir::new("moulinette") {
         Ok(t) => t,
@@ -158,37 +773,76 @@ pub fn create_environment(workdir: Option<&String>, rootfs: Option<&String>) ->
 
     info!(target:"safe_env", "env path {:?}", tmp_dir);
 
-    let cpy_options: CopyOptions = CopyOptions {
-        overwrite: true,
-        skip_exist: false,
-        buffer_size: 64000,
-        copy_inside: true,
-        content_only: true,
-        depth: 0,
+    let (image_config, rootfs) = match &options.rootfs {
+        Some(source) => populate_rootfs(
+            source,
+            tmp_dir.path(),
+            options.diff_dir.as_deref(),
+            pull_options,
+        )?,
+        None => (ImageConfig::default(), PathBuf::from(tmp_dir.path())),
     };
 
-    if let Some(rfs) = rootfs {
-        // If we cannot pull the docker image we try to copy the rootfs from the host
-        if let Err(e) = docker_image::download(rfs, tmp_dir.path()) {
-            warn!(target:"rootfs", "{:?}", e);
-            if let Err(_) = fs_extra::dir::copy(rfs, tmp_dir.path(), &cpy_options) {
-                return Err(SafeEnvError::IOError(std::io::Error::last_os_error()));
+    if let Err(e) = inject::copy_into(&rootfs, &options.copies) {
+        error!(target:"inject", "{:?}", e);
+        return Err(SafeEnvError::Inject(e));
+    }
+
+    // The sources are reached through the old root once we switched
+    let mut binds: Vec<BindMount> = Vec::new();
+
+    for bind in &options.binds {
+        match fs::canonicalize(&bind.source) {
+            Ok(source) => binds.push(BindMount {
+                source,
+                target: resolve_target(&rootfs, &bind.target)?,
+                read_only: bind.read_only,
+            }),
+            Err(e) => {
+                error!(target:"bind", "{:?} not found", bind.source);
+                return Err(SafeEnvError::IOError(e));
             }
         }
     }
 
+    let mut tmpfs_mounts: Vec<TmpfsMount> = Vec::new();
+
+    for tmpfs in options.tmpfs_mounts() {
+        tmpfs_mounts.push(TmpfsMount {
+            path: resolve_target(&rootfs, &tmpfs.path)?,
+            size: tmpfs.size,
+        });
+    }
+
+    // The rootfs is removed from inside the sandbox once the run is over
+    let host_parent = File::open(tmp_dir.path().parent().unwrap_or_else(|| Path::new("/")))
+        .map_err(SafeEnvError::IOError)?;
+
     // Update the path of the oldroot
-    let oldroot = switch_root(tmp_dir.path())?;
+    let oldroot = switch_root(&rootfs)?;
 
-    mount_os_fs(&oldroot)?;
+    mount_os_fs()?;
+
+    for tmpfs in &tmpfs_mounts {
+        mount_tmpfs(tmpfs)?;
+    }
 
     // Mount student files
-    if let Some(w) = workdir {
-        let p = format!("{}/{}", oldroot.as_os_str().to_str().unwrap(), w);
-        mount_workdir(Path::new(&p))?;
+    for bind in &binds {
+        mount_bind(bind, oldroot)?;
     }
 
     clean_oldrootfs(&oldroot)?;
 
-    Ok(())
+    // Mount points cannot be created anymore after this
+    if options.read_only {
+        remount_root_read_only()?;
+    }
+
+    // Kept by named sandboxes until delete, removed after the run otherwise
+    Ok(Environment {
+        image_config,
+        host_dir: tmp_dir.into_path(),
+        host_parent,
+    })
 }
@@ -0,0 +1,337 @@
+use log::{error, info, warn};
+use nix::{
+    errno::Errno,
+    sched::{setns, CloneFlags},
+    sys::{
+        signal::{kill, Signal},
+        wait::wait,
+    },
+    unistd::{chdir, chroot, fchdir, Pid},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    os::unix::{io::AsRawFd, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::cgroup::{CgroupError, CgroupV2};
+use crate::docker_image::ImageConfig;
+
+const STATE_ROOT: &str = "/run/moulinette";
+const STATE_FILE: &str = "state.json";
+
+/// Hidden subcommand of the process keeping a sandbox alive
+pub const HOLD_COMMAND: &str = "__hold";
+
+/// Namespaces joined by exec, the mount one last since it changes our root.
+/// The cgroup one is joined by the programs, once they are in the cgroup.
+const NAMESPACES: [(&str, CloneFlags); 5] = [
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("net", CloneFlags::CLONE_NEWNET),
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+];
+
+/// How long we wait for the processes of a sandbox to die
+const DELETE_ATTEMPTS: u32 = 50;
+const DELETE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum SandboxError {
+    IOError(std::io::Error),
+    Parse,
+    InvalidName(String),
+    AlreadyExists(String),
+    NotFound(String),
+    NotRunning(String),
+    Setns(Errno),
+    Chroot(Errno),
+    Kill(Errno),
+    Cgroup(CgroupError),
+}
+
+impl std::fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SandboxError::IOError(e) => write!(f, "{}", e),
+            SandboxError::Parse => write!(f, "cannot parse the sandbox state"),
+            SandboxError::InvalidName(name) => write!(f, "invalid sandbox name {}", name),
+            SandboxError::AlreadyExists(name) => write!(f, "{} already exists", name),
+            SandboxError::NotFound(name) => write!(f, "{} not found", name),
+            SandboxError::NotRunning(name) => write!(f, "{} is not running", name),
+            SandboxError::Setns(e) => write!(f, "setns: {}", e),
+            SandboxError::Chroot(e) => write!(f, "chroot: {}", e),
+            SandboxError::Kill(e) => write!(f, "kill: {}", e),
+            SandboxError::Cgroup(e) => write!(f, "cgroup: {}", e),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, SandboxError>;
+
+/// What is needed to enter and clean a named sandbox
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SandboxState {
+    pub name: String,
+    /// Process holding the namespaces, as seen from the host
+    pub pid: u32,
+    pub hostname: String,
+    /// Directory of the host the rootfs was built in
+    pub host_dir: PathBuf,
+    pub image_config: ImageConfig,
+    /// Seconds since the epoch
+    pub created: u64,
+}
+
+/// Names are used for the cgroup, the hostname and the state directory
+pub fn validate_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn state_path(name: &str) -> Result<PathBuf> {
+    if !validate_name(name) {
+        return Err(SandboxError::InvalidName(String::from(name)));
+    }
+
+    Ok(Path::new(STATE_ROOT).join(name))
+}
+
+/// State directory of a sandbox being created, opened before the switch of root
+pub struct StateDir {
+    dir: File,
+}
+
+impl StateDir {
+    pub fn create(name: &str) -> Result<StateDir> {
+        let path = state_path(name)?;
+
+        if let Err(e) = fs::create_dir_all(STATE_ROOT) {
+            return Err(SandboxError::IOError(e));
+        }
+
+        match fs::create_dir(&path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                error!(target:"sandbox", "{} already exists", name);
+                return Err(SandboxError::AlreadyExists(String::from(name)));
+            }
+            Err(e) => return Err(SandboxError::IOError(e)),
+        }
+
+        let dir = File::open(&path).map_err(SandboxError::IOError)?;
+
+        Ok(StateDir { dir })
+    }
+
+    /// Works from inside the sandbox, through our descriptor of the directory
+    pub fn save(&self, state: &SandboxState) -> Result<()> {
+        let path =
+            PathBuf::from(format!("/proc/self/fd/{}", self.dir.as_raw_fd())).join(STATE_FILE);
+
+        let data = match serde_json::to_vec_pretty(state) {
+            Ok(d) => d,
+            Err(_) => return Err(SandboxError::Parse),
+        };
+
+        fs::write(path, data).map_err(SandboxError::IOError)
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn load(name: &str) -> Result<SandboxState> {
+    let data = match fs::read(state_path(name)?.join(STATE_FILE)) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(SandboxError::NotFound(String::from(name)))
+        }
+        Err(e) => return Err(SandboxError::IOError(e)),
+    };
+
+    match serde_json::from_slice(&data) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(SandboxError::Parse),
+    }
+}
+
+/// Returns every sandbox and whether its holder is still running
+pub fn list() -> Result<Vec<(SandboxState, bool)>> {
+    let entries = match fs::read_dir(STATE_ROOT) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(SandboxError::IOError(e)),
+    };
+
+    let mut sandboxes: Vec<(SandboxState, bool)> = Vec::new();
+
+    for entry in entries {
+        let entry = entry.map_err(SandboxError::IOError)?;
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        match load(&name) {
+            Ok(state) => {
+                let alive = is_alive(state.pid);
+                sandboxes.push((state, alive));
+            }
+            // Being created, or left behind by a failed create
+            Err(e) => warn!(target:"sandbox", "{}: {:?}", name, e),
+        }
+    }
+
+    sandboxes.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+
+    Ok(sandboxes)
+}
+
+fn is_alive(pid: u32) -> bool {
+    kill(Pid::from_raw(pid as i32), None).is_ok()
+}
+
+/// Starts the process keeping the namespaces alive, it is the init of the
+/// pid namespace. enter runs in it before exec. Returns its pid.
+pub fn spawn_holder<F>(enter: F) -> Result<u32>
+where
+    F: FnMut() -> std::io::Result<()> + Send + Sync + 'static,
+{
+    let mut command = Command::new("/proc/self/exe");
+
+    unsafe {
+        command.pre_exec(enter);
+    }
+
+    // Our binary is not in the rootfs but the link still leads to it
+    let child = command
+        .arg(HOLD_COMMAND)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(SandboxError::IOError)?;
+
+    info!(target:"sandbox", "holder {} started", child.id());
+
+    Ok(child.id())
+}
+
+/// Body of the holder, reaps the orphans of the sandbox forever
+pub fn hold() -> ! {
+    loop {
+        if wait().is_err() {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+/// Joins the namespaces and the root of a running sandbox. Returns its cgroup
+/// namespace, for the programs to join.
+pub fn enter(state: &SandboxState) -> Result<File> {
+    if !is_alive(state.pid) {
+        return Err(SandboxError::NotRunning(state.name.clone()));
+    }
+
+    let proc_dir = PathBuf::from(format!("/proc/{}", state.pid));
+
+    // Opened beforehand, /proc of the host is out of reach once in the mount namespace
+    let root = File::open(proc_dir.join("root")).map_err(SandboxError::IOError)?;
+    let cgroup_ns =
+        File::open(proc_dir.join("ns").join("cgroup")).map_err(SandboxError::IOError)?;
+
+    let mut namespaces: Vec<(File, CloneFlags)> = Vec::new();
+
+    for (name, flag) in NAMESPACES {
+        let file = File::open(proc_dir.join("ns").join(name)).map_err(SandboxError::IOError)?;
+        namespaces.push((file, flag));
+    }
+
+    for (file, flag) in &namespaces {
+        if let Err(e) = setns(file.as_raw_fd(), *flag) {
+            error!(target:"sandbox", "setns {:?} failed", flag);
+            return Err(SandboxError::Setns(e));
+        }
+    }
+
+    let chrooted = fchdir(root.as_raw_fd())
+        .and_then(|_| chroot("."))
+        .and_then(|_| chdir("/"));
+
+    if let Err(e) = chrooted {
+        error!(target:"sandbox", "chroot failed");
+        return Err(SandboxError::Chroot(e));
+    }
+
+    info!(target:"sandbox", "entered {}", state.name);
+
+    Ok(cgroup_ns)
+}
+
+/// Removes a cgroup once its last process is gone, killed ones take a moment
+pub fn destroy_cgroup(cgroup: &CgroupV2) -> Result<()> {
+    let mut destroyed = cgroup.destroy();
+    let mut attempts = 0;
+
+    while destroyed.is_err() && attempts < DELETE_ATTEMPTS {
+        thread::sleep(DELETE_INTERVAL);
+        attempts += 1;
+        destroyed = cgroup.destroy();
+    }
+
+    destroyed.map_err(SandboxError::Cgroup)
+}
+
+/// Kills the processes of a sandbox and removes everything it left on the host
+pub fn delete(name: &str) -> Result<()> {
+    let state = match load(name) {
+        Ok(s) => s,
+        // Left behind by a create which failed before starting the holder
+        Err(SandboxError::NotFound(_)) if state_path(name)?.is_dir() => {
+            warn!(target:"sandbox", "{} was never started", name);
+            return fs::remove_dir_all(state_path(name)?).map_err(SandboxError::IOError);
+        }
+        Err(e) => return Err(e),
+    };
+
+    if is_alive(state.pid) {
+        // The kernel kills the whole pid namespace with its init
+        if let Err(e) = kill(Pid::from_raw(state.pid as i32), Signal::SIGKILL) {
+            return Err(SandboxError::Kill(e));
+        }
+    }
+
+    let mut attempts = 0;
+
+    while is_alive(state.pid) && attempts < DELETE_ATTEMPTS {
+        thread::sleep(DELETE_INTERVAL);
+        attempts += 1;
+    }
+
+    // The cgroup is busy until its last process is gone
+    match CgroupV2::open(&state.name) {
+        Ok(cgroup) => destroy_cgroup(&cgroup)?,
+        Err(_) => warn!(target:"sandbox", "{} has no cgroup", name),
+    }
+
+    if let Err(e) = fs::remove_dir_all(&state.host_dir) {
+        warn!(target:"sandbox", "cannot remove {:?}: {}", state.host_dir, e);
+    }
+
+    fs::remove_dir_all(state_path(name)?).map_err(SandboxError::IOError)?;
+
+    info!(target:"sandbox", "{} deleted", name);
+
+    Ok(())
+}
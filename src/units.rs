@@ -0,0 +1,76 @@
+/// Parses a size in bytes such as 512, 64k, 1.5G or 10MiB, units are powers of 1024
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+
+    let (number, unit) = size.split_at(split);
+
+    let number: f64 = number.parse().ok()?;
+
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return None,
+    };
+
+    let bytes = number * multiplier as f64;
+
+    if !bytes.is_finite() || bytes > u64::MAX as f64 {
+        return None;
+    }
+
+    Some(bytes as u64)
+}
+
+/// Formats a size in bytes for humans, as 1.5 MiB
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = size as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", size),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("512b"), Some(512));
+        assert_eq!(parse_size("64k"), Some(64 * 1024));
+        assert_eq!(parse_size("10MiB"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("1.5G"), Some(3 * 512 * 1024 * 1024));
+        assert_eq!(parse_size(" 2t "), Some(2 << 40));
+    }
+
+    #[test]
+    fn parse_invalid_sizes() {
+        for invalid in ["", "k", "12x", "1.2.3m", "-1", "99999999999999999999t"] {
+            assert_eq!(parse_size(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+    }
+}
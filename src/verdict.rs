@@ -0,0 +1,61 @@
+use log::warn;
+use nix::sys::signal::Signal;
+use std::{fmt, os::unix::process::ExitStatusExt, process::ExitStatus};
+
+use crate::cgroup::MemoryEvents;
+
+/// How the program of the sandbox ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Exited(i32),
+    /// Killed by a signal, the OOM killer aside
+    Signaled(i32),
+    OutOfMemory,
+}
+
+impl Verdict {
+    /// The memory events are read before and after the run, a named sandbox
+    /// keeps counting those of the previous programs
+    pub fn judge(status: ExitStatus, before: &MemoryEvents, after: &MemoryEvents) -> Verdict {
+        let oom_kills = after.oom_kill.saturating_sub(before.oom_kill);
+
+        let verdict = match (status.code(), status.signal()) {
+            (Some(code), _) => Verdict::Exited(code),
+            (None, Some(signal)) if signal == Signal::SIGKILL as i32 && oom_kills > 0 => {
+                Verdict::OutOfMemory
+            }
+            (None, Some(signal)) => Verdict::Signaled(signal),
+            // Stopped or continued, wait does not report those
+            (None, None) => Verdict::Signaled(0),
+        };
+
+        // Another process of the sandbox was killed, the program survived it
+        if oom_kills > 0 && verdict != Verdict::OutOfMemory {
+            warn!(target:"verdict", "{} processes killed by the OOM killer", oom_kills);
+        }
+
+        verdict
+    }
+
+    /// Exit code of moulinette, as a shell would report it
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Verdict::Exited(code) => *code,
+            Verdict::Signaled(signal) => 128 + signal,
+            Verdict::OutOfMemory => 128 + Signal::SIGKILL as i32,
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Exited(code) => write!(f, "exited with code {}", code),
+            Verdict::Signaled(signal) => match Signal::try_from(*signal) {
+                Ok(s) => write!(f, "killed by {}", s),
+                Err(_) => write!(f, "killed by signal {}", signal),
+            },
+            Verdict::OutOfMemory => write!(f, "killed by the OOM killer"),
+        }
+    }
+}